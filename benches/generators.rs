@@ -48,6 +48,12 @@ pub struct RemoveClustered<T: Arbitrary> {
     pub commands: Vec<Command<T>>,
 }
 
+#[derive(Clone, Debug)]
+pub struct RemoveForward<T: Arbitrary> {
+    pub data: Vec<T>,
+    pub commands: Vec<Command<T>>,
+}
+
 #[derive(Clone, Debug)]
 pub struct InsertRemoveScatteredEmpty<T: Arbitrary> {
     pub commands: Vec<Command<T>>,
@@ -172,6 +178,37 @@ impl<T: Arbitrary> Arbitrary for RemoveClustered<T> {
     }
 }
 
+impl<T: Arbitrary> Arbitrary for RemoveForward<T> {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let data: Vec<T> = Arbitrary::arbitrary(g);
+        if data.len() < 1 {
+            return Self::arbitrary(g);
+        }
+
+        let removes = g.gen_range(0, data.len());
+        let mut commands = Vec::with_capacity(removes);
+        let mut removed = 0;
+
+        while removed < removes {
+            let idx = g.gen_range(0, data.len()-removed);
+            let cluster_size = cmp::min(
+                g.gen_range(1, removes-removed+1),
+                data.len()-removed-idx);
+
+            for _ in (0 .. cluster_size) {
+                commands.push(Remove(idx));
+            }
+
+            removed += cluster_size;
+        }
+
+        RemoveForward {
+            data: data,
+            commands: commands,
+        }
+    }
+}
+
 fn insert_remove_scattered<T, G: Gen>(g: &mut G, mut elements: usize, weight: u32) -> (Vec<Command<T>>, usize)
     where T: Arbitrary
 {
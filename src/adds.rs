@@ -0,0 +1,340 @@
+//! The add buffer of a `PieceTable`, holding every element inserted into it.
+//!
+//! The elements are stored in reference counted chunks of fixed capacity, so clones and snapshots share them with the table instead of copying them.
+//! Elements are only ever appended to a chunk, so every holder of a chunk can keep appending to it, as long as nothing was appended after the elements it sees.
+//! Each holder only reads the elements it has seen, so those appended by others after them are never noticed.
+//! A holder that cannot append, or fills the last chunk, starts a new one, twice as large as the last if that was full,
+//! so only `O(log n)` chunks hold `n` elements, plus one for every time holders append different elements after the same ones.
+//! Positions count from the start of the first chunk, and the elements of a piece may lie in several chunks.
+//!
+//! The line index keeps the positions of the line breaks in the add buffer the same way, as they are only appended to as well.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The capacity of the first chunk, unless more is reserved.
+const FIRST_CHUNK: usize = 64;
+
+/// Memory for `capacity` elements, of which the first `len` have been appended and never change.
+struct Chunk<T> {
+    // Allocated by a `Vec` with `capacity`, and owned by the chunk.
+    ptr: *mut T,
+    capacity: usize,
+    // Only ever grows while the chunk is shared, and every holder owning the elements up to it claims the next one by advancing it.
+    len: AtomicUsize,
+}
+
+// The elements are only shared by reference, and appended by the holder that claimed their place.
+unsafe impl<T: Send + Sync> Send for Chunk<T> {}
+unsafe impl<T: Send + Sync> Sync for Chunk<T> {}
+
+impl<T> Chunk<T> {
+    fn with_capacity(capacity: usize) -> Chunk<T> {
+        let mut memory = ManuallyDrop::new(Vec::with_capacity(capacity));
+        Chunk {
+            ptr: memory.as_mut_ptr(),
+            capacity: memory.capacity(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of elements appended by any holder.
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Append `value` after the first `seen` elements, unless the chunk is full or something was appended after them.
+    /// Returns `value` if it was not appended.
+    fn push(&self, seen: usize, value: T) -> Result<(), T> {
+        if seen == self.capacity || self.len.compare_exchange(seen, seen + 1, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return Err(value);
+        }
+
+        // The place was claimed above, and no holder reads it before having seen the element written.
+        unsafe { ptr::write(self.ptr.add(seen), value) };
+        Ok(())
+    }
+
+    /// Append the elements of `iter` after the first `seen`, which must be all appended, until the chunk is full.
+    /// Returns the number of elements appended.
+    fn fill<I>(&mut self, seen: usize, iter: &mut I) -> usize where I: Iterator<Item=T> {
+        let mut len = seen;
+        while len < self.capacity {
+            match iter.next() {
+                Some(value) => {
+                    unsafe { ptr::write(self.ptr.add(len), value) };
+                    len += 1;
+                    *self.len.get_mut() = len;
+                },
+                None => break,
+            }
+        }
+        len - seen
+    }
+
+    /// Drop the elements from `len`, which no other holder can see.
+    fn truncate(&mut self, len: usize) {
+        let appended = *self.len.get_mut();
+        if len < appended {
+            *self.len.get_mut() = len;
+            unsafe { ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.ptr.add(len), appended - len)) };
+        }
+    }
+
+    /// The elements from `from` to `to`, which must have been appended.
+    fn slice(&self, from: usize, to: usize) -> &[T] {
+        debug_assert!(from <= to && to <= self.len());
+        unsafe { slice::from_raw_parts(self.ptr.add(from), to - from) }
+    }
+}
+
+impl<T> Drop for Chunk<T> {
+    fn drop(&mut self) {
+        unsafe { drop(Vec::from_raw_parts(self.ptr, *self.len.get_mut(), self.capacity)) };
+    }
+}
+
+pub(crate) struct Adds<T> {
+    // Every chunk along with the position of its first element, in order.
+    chunks: Vec<(usize, Arc<Chunk<T>>)>,
+    len: usize,
+}
+
+impl<T> Adds<T> {
+    pub fn with_capacity(capacity: usize) -> Adds<T> {
+        let mut adds = Adds {
+            chunks: Vec::new(),
+            len: 0,
+        };
+
+        if capacity > 0 {
+            adds.chunks.push((0, Arc::new(Chunk::with_capacity(capacity))));
+        }
+        adds
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The number of elements that can be appended without allocating, counting those already stored.
+    pub fn capacity(&self) -> usize {
+        self.len + self.room()
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        if self.room() < additional {
+            self.grow(additional);
+        }
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.forget_unseen();
+        self.append(value);
+    }
+
+    pub fn extend_from_slice(&mut self, items: &[T]) where T: Clone {
+        self.extend(items.iter().cloned());
+    }
+
+    pub fn extend<I>(&mut self, iterable: I) where I: IntoIterator<Item=T> {
+        let mut iter = iterable.into_iter();
+        self.reserve(iter.size_hint().0);
+        self.forget_unseen();
+
+        while let Some(value) = iter.next() {
+            self.append(value);
+
+            // Nothing else can append to a chunk only this buffer holds, so the rest goes in without claiming every place.
+            let len = self.len;
+            if let Some(&mut (start, ref mut chunk)) = self.chunks.last_mut() {
+                if let Some(chunk) = Arc::get_mut(chunk) {
+                    self.len += chunk.fill(len - start, &mut iter);
+                    if self.len - start < chunk.capacity {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remove the last element, unless it is shared.
+    /// Returns `false` if nothing was removed.
+    pub fn pop(&mut self) -> bool {
+        self.drop_empty();
+        let len = self.len;
+        let popped = match self.chunks.last_mut() {
+            Some(&mut (start, ref mut chunk)) => match Arc::get_mut(chunk) {
+                Some(chunk) => {
+                    chunk.truncate(len - start - 1);
+                    true
+                },
+                None => false,
+            },
+            None => false,
+        };
+
+        if popped {
+            self.len -= 1;
+        }
+        popped
+    }
+
+    /// Forget the elements from `len`, which stay in place for those sharing them.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.len = len;
+            self.drop_empty();
+            self.forget_unseen();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.len = 0;
+    }
+
+    pub fn get(&self, pos: usize) -> &T {
+        let chunk = self.chunk(pos);
+        let start = self.chunks[chunk].0;
+        &self.seen(chunk)[pos - start]
+    }
+
+    /// The elements from `start` up to `end`, or up to the end of the chunk holding `start` if that comes first.
+    pub fn slice_from(&self, start: usize, end: usize) -> &[T] {
+        if start == end {
+            return &[];
+        }
+
+        let chunk = self.chunk(start);
+        let chunk_start = self.chunks[chunk].0;
+        let seen = self.seen(chunk);
+        &seen[start - chunk_start .. seen.len().min(end - chunk_start)]
+    }
+
+    /// The elements from `start` up to `end`, or from the start of the chunk holding the element before `end` if that comes later.
+    pub fn slice_to(&self, start: usize, end: usize) -> &[T] {
+        if start == end {
+            return &[];
+        }
+
+        let chunk = self.chunk(end - 1);
+        let chunk_start = self.chunks[chunk].0;
+        &self.seen(chunk)[start.max(chunk_start) - chunk_start .. end - chunk_start]
+    }
+
+    /// The number of elements before the first one for which `pred` does not hold, which must not hold for any element after it either.
+    ///
+    /// Takes `O(log n)` time.
+    pub fn partition_point<P>(&self, pred: P) -> usize where P: Fn(&T) -> bool {
+        // Only the last chunk can be empty, so the chunks whose last element satisfies `pred` come first.
+        let (mut chunk, mut end) = (0, self.chunks.len());
+        while chunk < end {
+            let mid = (chunk + end) / 2;
+            if self.seen(mid).last().is_some_and(&pred) {
+                chunk = mid + 1;
+            } else {
+                end = mid;
+            }
+        }
+
+        match self.chunks.get(chunk) {
+            Some(&(start, _)) => start + self.seen(chunk).partition_point(&pred),
+            None => self.len,
+        }
+    }
+
+    /// Append `value` after the elements of this buffer, in a new chunk if it cannot go in the last one.
+    fn append(&mut self, value: T) {
+        let value = match self.chunks.last() {
+            Some(&(start, ref chunk)) => chunk.push(self.len - start, value),
+            None => Err(value),
+        };
+        if let Err(value) = value {
+            self.grow(1);
+            let (start, ref chunk) = *self.chunks.last().unwrap();
+            assert!(chunk.push(self.len - start, value).is_ok());
+        }
+
+        self.len += 1;
+    }
+
+    /// The index of the chunk holding the element at `pos`.
+    fn chunk(&self, pos: usize) -> usize {
+        self.chunks.partition_point(|&(start, _)| start <= pos) - 1
+    }
+
+    /// The elements of the chunk at `chunk` that belong to this buffer.
+    fn seen(&self, chunk: usize) -> &[T] {
+        let (start, ref elements) = self.chunks[chunk];
+        let end = self.chunks.get(chunk + 1).map_or(self.len, |&(next, _)| next);
+        elements.slice(0, end - start)
+    }
+
+    /// The number of elements that can be appended to the last chunk.
+    fn room(&self) -> usize {
+        match self.chunks.last() {
+            Some(&(start, ref chunk)) if chunk.len() == self.len - start => chunk.capacity - chunk.len(),
+            _ => 0,
+        }
+    }
+
+    /// Start a new chunk with room for at least `additional` elements.
+    fn grow(&mut self, additional: usize) {
+        self.drop_empty();
+
+        // Doubling the chunks as they fill keeps their number logarithmic, while chunks started for other reasons stay small.
+        let capacity = match self.chunks.last() {
+            Some(&(start, ref chunk)) if self.len - start == chunk.capacity => chunk.capacity * 2,
+            _ => FIRST_CHUNK,
+        };
+        self.chunks.push((self.len, Arc::new(Chunk::with_capacity(capacity.max(additional)))));
+    }
+
+    /// Drop the elements of the last chunk that were appended by holders no longer around, so they can be appended to again.
+    fn forget_unseen(&mut self) {
+        let len = self.len;
+        if let Some(&mut (start, ref mut chunk)) = self.chunks.last_mut() {
+            if let Some(chunk) = Arc::get_mut(chunk) {
+                chunk.truncate(len - start);
+            }
+        }
+    }
+
+    /// Forget the last chunk while it is empty, as it holds nothing even if shared, so only the last chunk is ever empty.
+    fn drop_empty(&mut self) {
+        while self.chunks.last().is_some_and(|&(start, _)| start >= self.len) {
+            self.chunks.pop();
+        }
+    }
+}
+
+impl<T> Clone for Adds<T> {
+    /// Shares the chunks, which either can keep appending to until the other does.
+    fn clone(&self) -> Adds<T> {
+        Adds {
+            chunks: self.chunks.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Adds<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries((0..self.chunks.len()).flat_map(|chunk| self.seen(chunk))).finish()
+    }
+}
+
+impl<T: Hash> Hash for Adds<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for chunk in 0..self.chunks.len() {
+            self.seen(chunk).hash(state);
+        }
+        self.len.hash(state);
+    }
+}
@@ -0,0 +1,233 @@
+//! Anchors, offsets in a `PieceTable` that stay attached to the same element as it is edited.
+//!
+//! The anchors are kept in order as marks, so an edit takes `O(log a)` time for `a` anchors, plus `O(1)` for every anchor whose element it removes.
+//! The latest edits are also kept as moves, which decorations replay on their own marks instead of reading all their anchors again.
+//! An anchor also remembers where its element is stored in the source or the add buffer, which never changes,
+//! so after undo, redo or rollback it is found again by looking for that element in the restored pieces.
+
+use std::collections::VecDeque;
+
+use Buffer;
+use marks::Marks;
+use tree::Tree;
+
+/// The side an anchor sticks to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Gravity {
+    /// The anchor follows the element before it, so it stays in place when elements are inserted at its offset.
+    Left,
+    /// The anchor follows the element after it, so it moves past elements inserted at its offset.
+    Right,
+}
+
+/// A handle to an anchor, returned by `PieceTable::create_anchor`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Anchor {
+    slot: usize,
+    generation: usize,
+}
+
+/// A move of the anchors after a position, in the same order as the edits.
+#[derive(Debug, Clone, Copy, Hash)]
+pub(crate) enum Move {
+    Inserted(usize, usize),
+    Removed(usize, usize),
+}
+
+/// The number of moves kept for replaying, beyond which decorations read all their anchors again instead.
+const KEPT_MOVES: usize = 256;
+
+#[derive(Debug, Clone, Copy, Hash)]
+struct State {
+    gravity: Gravity,
+    // Where the element the anchor follows is stored, unless it is at the start or end with nothing on its side.
+    element: Option<(Buffer, usize)>,
+    deleted: bool,
+}
+
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct Anchors {
+    marks: Marks<State>,
+    // The generation of every slot, bumped whenever it is freed, so handles to removed anchors are recognized.
+    generations: Vec<usize>,
+    // The latest moves, after the `moved - moves.len()` moves before them were forgotten.
+    moves: VecDeque<Move>,
+    moved: usize,
+    // The number of moves when the anchors were last moved in a way that cannot be replayed.
+    reset: usize,
+}
+
+impl Anchors {
+    pub fn new() -> Anchors {
+        Anchors {
+            marks: Marks::new(),
+            generations: Vec::new(),
+            moves: VecDeque::new(),
+            moved: 0,
+            reset: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marks.is_empty()
+    }
+
+    pub fn create(&mut self, offset: usize, gravity: Gravity, element: Option<(Buffer, usize)>) -> Anchor {
+        // An anchor is a mark whose ends are both at its offset.
+        let slot = self.marks.insert((offset, gravity), (offset, gravity), State {
+            gravity: gravity,
+            element: element,
+            deleted: false,
+        });
+
+        if slot == self.generations.len() {
+            self.generations.push(0);
+        }
+
+        Anchor {
+            slot: slot,
+            generation: self.generations[slot],
+        }
+    }
+
+    /// Forget `anchor`, returning `false` if it was already removed.
+    pub fn remove(&mut self, anchor: Anchor) -> bool {
+        if self.get(anchor).is_none() {
+            return false;
+        }
+
+        self.marks.remove(anchor.slot);
+        self.generations[anchor.slot] += 1;
+        true
+    }
+
+    /// The offset of `anchor` and whether its element was deleted, unless it was removed.
+    ///
+    /// Takes `O(log a)` time.
+    pub fn get(&self, anchor: Anchor) -> Option<(usize, bool)> {
+        if self.generations.get(anchor.slot) != Some(&anchor.generation) {
+            return None;
+        }
+
+        self.marks.get(anchor.slot).map(|(state, range)| (range.start, state.deleted))
+    }
+
+    /// The number of moves so far, counting those that cannot be replayed as one each.
+    pub fn moved(&self) -> usize {
+        self.moved
+    }
+
+    /// The moves made since there had been `moved` of them, unless some of them were forgotten or cannot be replayed.
+    pub fn moves_since(&self, moved: usize) -> Option<impl Iterator<Item=&Move>> {
+        let forgotten = self.moved - self.moves.len();
+        if moved < self.reset || moved < forgotten || moved > self.moved {
+            None
+        } else {
+            Some(self.moves.iter().skip(moved - forgotten))
+        }
+    }
+
+    fn record(&mut self, move_: Move) {
+        // Nothing can be attached to the anchors while there are none, so there is nothing to replay the moves on.
+        if self.is_empty() {
+            return;
+        }
+
+        if self.moves.len() == KEPT_MOVES {
+            self.moves.pop_front();
+        }
+        self.moves.push_back(move_);
+        self.moved += 1;
+    }
+
+    fn reset(&mut self) {
+        self.moves.clear();
+        self.moved += 1;
+        self.reset = self.moved;
+    }
+
+    /// Move the anchors after `idx`, where `length` elements were inserted.
+    pub fn inserted(&mut self, idx: usize, length: usize) {
+        self.record(Move::Inserted(idx, length));
+        self.marks.inserted(idx, length);
+    }
+
+    /// Move the anchors after `to`, where the elements from `from` were removed, and mark those that followed a removed element.
+    pub fn removed(&mut self, from: usize, to: usize) {
+        self.record(Move::Removed(from, to));
+        self.marks.removed(from, to, |state| state.deleted |= state.element.is_some());
+    }
+
+    /// Detach every anchor from its element, as all elements were removed and will not return, leaving `length` new ones.
+    /// Those with `Gravity::Right` and no element stay at the end, and all others end up at the start.
+    pub fn clear(&mut self, length: usize) {
+        self.reset();
+        self.marks.rebuild(|_, state, _| {
+            let offset = match (state.element, state.deleted, state.gravity) {
+                (None, false, Gravity::Right) => length,
+                _ => 0,
+            };
+            state.deleted |= state.element.is_some();
+            state.element = None;
+            (offset, offset)
+        });
+    }
+
+    /// Move the elements of the anchors in the add buffer to the positions given by `remap`, forgetting those it drops.
+    pub fn remap<F>(&mut self, remap: F) where F: Fn(usize) -> Option<usize> {
+        for state in self.marks.values_mut() {
+            if let Some((Buffer::Add, pos)) = state.element {
+                state.element = remap(pos).map(|pos| (Buffer::Add, pos));
+            }
+        }
+    }
+
+    /// Find the element of every anchor in `pieces`, which were restored from another revision and hold `length` elements.
+    ///
+    /// Takes `O(p log a + a log a)` time.
+    /// An anchor whose element is not among the pieces is marked deleted and kept at its offset, or at the end if that is past it.
+    pub fn restore(&mut self, pieces: &Tree, length: usize) {
+        self.reset();
+
+        // Elements sorted by where they are stored, with the add buffer first.
+        let mut elements = self.marks.values()
+            .filter_map(|(slot, state)| state.element.map(|(buffer, pos)| (buffer != Buffer::Add, pos, slot)))
+            .collect::<Vec<(bool, usize, usize)>>();
+        elements.sort();
+
+        // The offset of the element of every anchor found.
+        let mut found = vec![None; self.generations.len()];
+        let mut offset = 0;
+        for piece in pieces.iter() {
+            let key = (piece.buffer != Buffer::Add, piece.start);
+            let first = elements.partition_point(|&(buffer, pos, _)| (buffer, pos) < key);
+            let end = (key.0, piece.start + piece.length);
+
+            for &(_, pos, slot) in elements[first..].iter().take_while(|&&(buffer, pos, _)| (buffer, pos) < end) {
+                found[slot] = Some(offset + pos - piece.start);
+            }
+
+            offset += piece.length;
+        }
+
+        self.marks.rebuild(|slot, state, offset| {
+            let offset = match (found[slot], state.element, state.deleted) {
+                (Some(idx), _, _) => {
+                    state.deleted = false;
+                    match state.gravity {
+                        Gravity::Left => idx + 1,
+                        Gravity::Right => idx,
+                    }
+                },
+                (None, Some(_), _) => {
+                    state.deleted = true;
+                    offset.min(length)
+                },
+                (None, None, false) if state.gravity == Gravity::Left => 0,
+                (None, None, false) => length,
+                (None, None, true) => offset.min(length),
+            };
+            (offset, offset)
+        });
+    }
+}
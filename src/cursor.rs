@@ -0,0 +1,230 @@
+//! Cursors pointing at a position in a `PieceTable`.
+//!
+//! A cursor remembers the piece it is in and its offset into that piece, so moving within a piece takes constant time.
+//! Moving into a neighbouring piece takes `O(log p)` time, as does seeking to a position outside the current piece.
+
+use {Add, Piece, PieceTable};
+use tree::Tree;
+
+/// A position in the table along with the piece containing it.
+/// At the end of the table, `piece_idx` is the number of pieces and `piece` is empty.
+#[derive(Debug, Clone, Copy)]
+struct Spot {
+    idx: usize,
+    piece_idx: usize,
+    offset: usize,
+    piece: Piece,
+}
+
+impl Spot {
+    fn locate(pieces: &Tree, idx: usize) -> Spot {
+        match pieces.locate(idx) {
+            Some((piece_idx, offset)) => Spot {
+                idx: idx,
+                piece_idx: piece_idx,
+                offset: offset,
+                piece: *pieces.get(piece_idx),
+            },
+            None => Spot::piece_start(pieces, pieces.count(), idx),
+        }
+    }
+
+    /// The spot at the start of the piece at `piece_idx`, which is at position `idx`.
+    fn piece_start(pieces: &Tree, piece_idx: usize, idx: usize) -> Spot {
+        let piece = if piece_idx < pieces.count() {
+            *pieces.get(piece_idx)
+        } else {
+            Piece {
+                start: 0,
+                length: 0,
+                buffer: Add,
+                breaks: 0,
+            }
+        };
+
+        Spot {
+            idx: idx,
+            piece_idx: piece_idx,
+            offset: 0,
+            piece: piece,
+        }
+    }
+
+    fn move_left(&mut self, pieces: &Tree) -> bool {
+        if self.idx == 0 {
+            return false;
+        }
+
+        self.idx -= 1;
+
+        if self.offset > 0 {
+            self.offset -= 1;
+        } else {
+            self.piece_idx -= 1;
+            self.piece = *pieces.get(self.piece_idx);
+            self.offset = self.piece.length - 1;
+        }
+
+        true
+    }
+
+    fn move_right(&mut self, pieces: &Tree) -> bool {
+        if self.offset == self.piece.length {
+            return false;
+        }
+
+        self.idx += 1;
+        self.offset += 1;
+
+        if self.offset == self.piece.length {
+            *self = Spot::piece_start(pieces, self.piece_idx+1, self.idx);
+        }
+
+        true
+    }
+
+    fn seek(&mut self, pieces: &Tree, idx: usize) {
+        let start = self.idx - self.offset;
+
+        if idx >= start && idx < start + self.piece.length {
+            self.offset = idx - start;
+            self.idx = idx;
+        } else {
+            *self = Spot::locate(pieces, idx);
+        }
+    }
+
+    fn get<'a, T, S: AsRef<[T]>>(&self, table: &'a PieceTable<T, S>) -> Option<&'a T> {
+        if self.offset < self.piece.length {
+            Some(table.piece_element(&self.piece, self.offset))
+        } else {
+            None
+        }
+    }
+}
+
+/// A read-only cursor into a `PieceTable`, created by `PieceTable::cursor`.
+pub struct Cursor<'a, T: 'a, S: 'a = &'a [T]> {
+    table: &'a PieceTable<'a, T, S>,
+    spot: Spot,
+}
+
+impl<'a, T: 'a, S: 'a + AsRef<[T]>> Cursor<'a, T, S> {
+    pub(crate) fn new(table: &'a PieceTable<'a, T, S>, idx: usize) -> Cursor<'a, T, S> {
+        assert!(idx <= table.len());
+
+        Cursor {
+            table: table,
+            spot: Spot::locate(&table.pieces, idx),
+        }
+    }
+
+    /// The index the cursor points at.
+    pub fn position(&self) -> usize {
+        self.spot.idx
+    }
+
+    /// The element the cursor points at, or `None` at the end of the table.
+    pub fn get(&self) -> Option<&'a T> {
+        self.spot.get(self.table)
+    }
+
+    /// Move one element towards the start, returning `false` if already at the start.
+    pub fn move_left(&mut self) -> bool {
+        self.spot.move_left(&self.table.pieces)
+    }
+
+    /// Move one element towards the end, returning `false` if already at the end.
+    pub fn move_right(&mut self) -> bool {
+        self.spot.move_right(&self.table.pieces)
+    }
+
+    /// Move to `idx`, taking constant time if it is in the current piece.
+    ///
+    /// # Panics
+    /// Panics if not `idx <= len`.
+    pub fn seek(&mut self, idx: usize) {
+        assert!(idx <= self.table.len());
+        self.spot.seek(&self.table.pieces, idx);
+    }
+}
+
+/// A cursor into a `PieceTable` which can edit it, created by `PieceTable::cursor_mut`.
+///
+/// Edits go through the table, so typing, backspacing and deleting forwards from the cursor reuse the pieces of the previous edit.
+/// The cursor relocates itself in `O(log p)` time after each edit.
+pub struct CursorMut<'b, 'a: 'b, T: 'a, S: 'a = &'a [T]> {
+    table: &'b mut PieceTable<'a, T, S>,
+    spot: Spot,
+}
+
+impl<'b, 'a: 'b, T: 'a, S: 'a + AsRef<[T]>> CursorMut<'b, 'a, T, S> {
+    pub(crate) fn new(table: &'b mut PieceTable<'a, T, S>, idx: usize) -> CursorMut<'b, 'a, T, S> {
+        assert!(idx <= table.len());
+
+        CursorMut {
+            spot: Spot::locate(&table.pieces, idx),
+            table: table,
+        }
+    }
+
+    /// The index the cursor points at.
+    pub fn position(&self) -> usize {
+        self.spot.idx
+    }
+
+    /// The element the cursor points at, or `None` at the end of the table.
+    pub fn get(&self) -> Option<&T> {
+        self.spot.get(self.table)
+    }
+
+    /// Move one element towards the start, returning `false` if already at the start.
+    pub fn move_left(&mut self) -> bool {
+        self.spot.move_left(&self.table.pieces)
+    }
+
+    /// Move one element towards the end, returning `false` if already at the end.
+    pub fn move_right(&mut self) -> bool {
+        self.spot.move_right(&self.table.pieces)
+    }
+
+    /// Move to `idx`, taking constant time if it is in the current piece.
+    ///
+    /// # Panics
+    /// Panics if not `idx <= len`.
+    pub fn seek(&mut self, idx: usize) {
+        assert!(idx <= self.table.len());
+        self.spot.seek(&self.table.pieces, idx);
+    }
+
+    /// Insert `item` before the cursor, which keeps pointing at the same element.
+    pub fn insert(&mut self, item: T) {
+        let idx = self.spot.idx;
+        self.table.insert(idx, item);
+        self.spot = Spot::locate(&self.table.pieces, idx+1);
+    }
+
+    /// Remove the element the cursor points at, returning `false` if at the end of the table.
+    pub fn delete_forward(&mut self) -> bool {
+        let idx = self.spot.idx;
+        if idx == self.table.len() {
+            return false;
+        }
+
+        self.table.remove(idx);
+        self.spot = Spot::locate(&self.table.pieces, idx);
+        true
+    }
+
+    /// Remove the element before the cursor, returning `false` if at the start of the table.
+    pub fn delete_backward(&mut self) -> bool {
+        let idx = self.spot.idx;
+        if idx == 0 {
+            return false;
+        }
+
+        self.table.remove(idx-1);
+        self.spot = Spot::locate(&self.table.pieces, idx-1);
+        true
+    }
+}
@@ -0,0 +1,222 @@
+//! Decorations, values attached to ranges of a `PieceTable` that follow the elements as it is edited.
+//!
+//! Both ends of a decoration are anchors in the table, so they are moved by every edit and found again after undo.
+//! The decorations are also kept as marks of their own, ordered by start, which the first query after an edit brings up to date
+//! by replaying the moves the table recorded for its anchors, in `O(log d)` time per edit for `d` decorations.
+//! After undo, redo, rollback or clearing the table, or too many edits to replay, the ranges are read from the anchors again in `O(d log d)` time.
+
+use std::ops::{self, RangeBounds};
+use std::vec;
+
+use {Anchor, Gravity, PieceTable};
+use anchors::Move;
+use marks::Marks;
+
+/// A handle to a decoration, returned by `Decorations::insert`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Decoration {
+    slot: usize,
+    generation: usize,
+}
+
+#[derive(Debug, Clone, Hash)]
+struct Entry<D> {
+    start: Anchor,
+    end: Anchor,
+    value: D,
+}
+
+/// A collection of values attached to ranges of one `PieceTable`.
+///
+/// The ends of the ranges move along with the elements next to them, sticking to the side given by their `Gravity`.
+/// An end with `Gravity::Left` stays in place when inserting at it, so a start with `Gravity::Left` grows the range, while an end with `Gravity::Left` does not.
+/// A range whose elements are all removed becomes empty, and stays in the collection until removed.
+///
+/// A collection must only be used with the table its decorations were inserted into.
+#[derive(Debug, Clone, Hash)]
+pub struct Decorations<D> {
+    marks: Marks<Entry<D>>,
+    // The generation of every slot, bumped whenever it is freed, so handles to removed decorations are recognized.
+    generations: Vec<usize>,
+    // The number of moves of the table's anchors the marks have been brought up to date with.
+    synced: Option<usize>,
+}
+
+/// Struct for iterating the decorations intersecting a range, ordered by start.
+pub struct Intersecting<'d, D: 'd> {
+    marks: &'d Marks<Entry<D>>,
+    found: vec::IntoIter<(usize, ops::Range<usize>)>,
+}
+
+impl<D> Decorations<D> {
+
+    /// Construct an empty collection.
+    pub fn new() -> Decorations<D> {
+        Default::default()
+    }
+
+    /// The number of decorations.
+    pub fn len(&self) -> usize {
+        self.marks.len()
+    }
+
+    /// Returns `true` if there are no decorations.
+    pub fn is_empty(&self) -> bool {
+        self.marks.is_empty()
+    }
+
+    /// Attach `value` to the elements in `range` of `table`, with the start and the end sticking to the sides given by `start` and `end`.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::{Decorations, Gravity, PieceTable};
+    /// let src = b"let x = 1;".to_vec();
+    /// let mut table = PieceTable::new().src(&src);
+    /// let mut decorations = Decorations::new();
+    /// decorations.insert(&mut table, 4..5, Gravity::Left, Gravity::Right, "unused");
+    /// table.insert_slice(5, b"yz");
+    /// table.remove_range(0..4);
+    /// assert_eq!(vec![(0..3, &"unused")], decorations.intersecting(&table, 2..).collect::<Vec<_>>());
+    /// ```
+    pub fn insert<'a, T, S, R>(&mut self, table: &mut PieceTable<'a, T, S>, range: R, start: Gravity, end: Gravity, value: D) -> Decoration
+        where T: 'a, S: 'a + AsRef<[T]>, R: RangeBounds<usize>
+    {
+        let (from, to) = table.bounds(range);
+        assert!(from <= to && to <= table.len());
+
+        // The new mark is at the current offsets, so the others must be as well.
+        self.sync(table);
+
+        let entry = Entry {
+            start: table.create_anchor(from, start),
+            end: table.create_anchor(to, end),
+            value: value,
+        };
+        let slot = self.marks.insert((from, start), (to, end), entry);
+
+        if slot == self.generations.len() {
+            self.generations.push(0);
+        }
+        self.synced = Some(table.anchors.moved());
+
+        Decoration {
+            slot: slot,
+            generation: self.generations[slot],
+        }
+    }
+
+    /// Remove `decoration` and its anchors from `table`, returning its value.
+    /// Returns `None` if it was already removed.
+    ///
+    /// Takes `O(log d + log a)` time for `a` anchors in the table.
+    pub fn remove<'a, T, S>(&mut self, table: &mut PieceTable<'a, T, S>, decoration: Decoration) -> Option<D>
+        where T: 'a, S: 'a + AsRef<[T]>
+    {
+        self.get(decoration)?;
+        self.generations[decoration.slot] += 1;
+
+        self.marks.remove(decoration.slot).map(|entry| {
+            table.remove_anchor(entry.start);
+            table.remove_anchor(entry.end);
+            entry.value
+        })
+    }
+
+    /// The current range of `decoration` in `table`, or `None` if it was removed.
+    ///
+    /// Takes `O(log a)` time for `a` anchors in the table.
+    pub fn range<'a, T, S>(&self, table: &PieceTable<'a, T, S>, decoration: Decoration) -> Option<ops::Range<usize>>
+        where T: 'a, S: 'a + AsRef<[T]>
+    {
+        self.entry(decoration).map(|entry| {
+            let from = table.anchor_offset(entry.start);
+            let to = table.anchor_offset(entry.end);
+            from .. to.max(from)
+        })
+    }
+
+    /// The value of `decoration`, or `None` if it was removed.
+    pub fn get(&self, decoration: Decoration) -> Option<&D> {
+        self.entry(decoration).map(|entry| &entry.value)
+    }
+
+    /// The value of `decoration` for modifying it, or `None` if it was removed.
+    pub fn get_mut(&mut self, decoration: Decoration) -> Option<&mut D> {
+        if self.generations.get(decoration.slot) != Some(&decoration.generation) {
+            return None;
+        }
+        self.marks.value_mut(decoration.slot).map(|entry| &mut entry.value)
+    }
+
+    /// Return an iterator over the decorations sharing at least one element with `range` of `table`, along with their current ranges, ordered by start.
+    ///
+    /// Takes `O((k + 1) log d)` time for `k` decorations found, after replaying the edits made to `table` since the last query.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds.
+    pub fn intersecting<'a, 'd, T, S, R>(&'d mut self, table: &PieceTable<'a, T, S>, range: R) -> Intersecting<'d, D>
+        where T: 'a, S: 'a + AsRef<[T]>, R: RangeBounds<usize>
+    {
+        let (from, to) = table.bounds(range);
+        assert!(from <= to && to <= table.len());
+
+        self.sync(table);
+
+        Intersecting {
+            marks: &self.marks,
+            found: self.marks.overlapping(from, to).into_iter(),
+        }
+    }
+
+    fn entry(&self, decoration: Decoration) -> Option<&Entry<D>> {
+        if self.generations.get(decoration.slot) != Some(&decoration.generation) {
+            return None;
+        }
+        self.marks.value(decoration.slot)
+    }
+
+    /// Replay the moves of the table's anchors since the last time on the marks, or read the offsets of all anchors if that is not possible.
+    fn sync<'a, T, S>(&mut self, table: &PieceTable<'a, T, S>) where T: 'a, S: 'a + AsRef<[T]> {
+        let anchors = &table.anchors;
+
+        match self.synced.and_then(|synced| anchors.moves_since(synced)) {
+            Some(moves) => for move_ in moves {
+                match *move_ {
+                    Move::Inserted(idx, length) => self.marks.inserted(idx, length),
+                    Move::Removed(from, to) => self.marks.removed(from, to, |_| ()),
+                }
+            },
+            None => self.marks.rebuild(|_, entry, _| (table.anchor_offset(entry.start), table.anchor_offset(entry.end))),
+        }
+
+        self.synced = Some(anchors.moved());
+    }
+}
+
+impl<D> Default for Decorations<D> {
+    fn default() -> Decorations<D> {
+        Decorations {
+            marks: Marks::new(),
+            generations: Vec::new(),
+            synced: None,
+        }
+    }
+}
+
+impl<'d, D> Iterator for Intersecting<'d, D> {
+    type Item = (ops::Range<usize>, &'d D);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let marks = self.marks;
+        self.found.next().map(|(slot, range)| (range, &marks.value(slot).unwrap().value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.found.size_hint()
+    }
+}
+
+impl<'d, D> ExactSizeIterator for Intersecting<'d, D> {}
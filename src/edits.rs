@@ -0,0 +1,190 @@
+//! A log of the edits made to a `PieceTable`, for consumers that update incrementally.
+//!
+//! Edits are only recorded while someone is subscribed, and each is kept until every subscriber has seen it.
+//! Undo, redo and rollback swap in another piece tree rather than editing, so their edit is found by comparing the trees,
+//! skipping the pieces at both ends that refer to the same elements.
+//!
+//! With the line index enabled, the row and column of the ends of every edit are recorded as well, as incremental parsers need them.
+//! They are looked up in the piece trees before and after the edit, which share all unchanged nodes, so no elements are scanned.
+
+use Buffer;
+use tree::Tree;
+
+/// A change to a `PieceTable`: the `removed_len` elements at `start` were replaced by `inserted_len` elements.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Edit {
+    pub start: usize,
+    pub removed_len: usize,
+    pub inserted_len: usize,
+}
+
+/// A row and a column, both counting from zero, where the column counts elements since the start of the row.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct Point {
+    pub row: usize,
+    pub column: usize,
+}
+
+/// An edit described the way incremental parsers such as tree-sitter expect it, by its offsets and points before and after.
+///
+/// Offsets and columns count elements, which are bytes for a `PieceTable<u8>` or a `TextTable`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct InputEdit {
+    pub start_byte: usize,
+    pub old_end_byte: usize,
+    pub new_end_byte: usize,
+    pub start_position: Point,
+    pub old_end_position: Point,
+    pub new_end_position: Point,
+}
+
+/// A handle to a subscriber of the edits of a `PieceTable`, returned by `PieceTable::subscribe`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Subscription {
+    id: usize,
+}
+
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct EditLog {
+    edits: Vec<Edit>,
+    // The start, old end and new end of every edit, if the line index was enabled when it was made.
+    points: Vec<Option<[Point; 3]>>,
+    // The number of edits dropped from the front of `edits`, as every subscriber had seen them.
+    dropped: usize,
+    // The number of edits every subscriber has seen, counting dropped edits.
+    subscribers: Vec<Option<usize>>,
+}
+
+impl EditLog {
+    pub fn new() -> EditLog {
+        EditLog {
+            edits: Vec::new(),
+            points: Vec::new(),
+            dropped: 0,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Whether anyone is subscribed, so edits must be recorded.
+    pub fn is_active(&self) -> bool {
+        self.subscribers.iter().any(Option::is_some)
+    }
+
+    pub fn push(&mut self, edit: Edit, points: Option<[Point; 3]>) {
+        if self.is_active() && (edit.removed_len > 0 || edit.inserted_len > 0) {
+            self.edits.push(edit);
+            self.points.push(points);
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Subscription {
+        self.subscribers.push(Some(self.dropped + self.edits.len()));
+
+        Subscription {
+            id: self.subscribers.len() - 1,
+        }
+    }
+
+    pub fn unsubscribe(&mut self, subscription: Subscription) -> bool {
+        let subscribed = self.subscribers.get(subscription.id).is_some_and(Option::is_some);
+        if subscribed {
+            self.subscribers[subscription.id] = None;
+            self.drop_seen();
+        }
+        subscribed
+    }
+
+    /// The edits `subscription` has not seen yet, which it has seen afterwards.
+    pub fn poll(&mut self, subscription: Subscription) -> &[Edit] {
+        let first = self.advance(subscription);
+        &self.edits[first..]
+    }
+
+    /// The edits `subscription` has not seen yet along with their points, which it has seen afterwards.
+    pub fn poll_input(&mut self, subscription: Subscription) -> Vec<InputEdit> {
+        let first = self.advance(subscription);
+
+        self.edits[first..].iter().zip(&self.points[first..]).map(|(edit, points)| {
+            let [start, old_end, new_end] = points.expect("line index not enabled when the edit was made");
+
+            InputEdit {
+                start_byte: edit.start,
+                old_end_byte: edit.start + edit.removed_len,
+                new_end_byte: edit.start + edit.inserted_len,
+                start_position: start,
+                old_end_position: old_end,
+                new_end_position: new_end,
+            }
+        }).collect()
+    }
+
+    /// Mark every edit as seen by `subscription`, returning the index of the first one it had not seen.
+    fn advance(&mut self, subscription: Subscription) -> usize {
+        let seen = self.subscribers.get(subscription.id).and_then(|&seen| seen)
+            .expect("subscription was cancelled");
+
+        self.drop_seen();
+        self.subscribers[subscription.id] = Some(self.dropped + self.edits.len());
+
+        seen - self.dropped
+    }
+
+    /// Drop the edits every subscriber has seen.
+    fn drop_seen(&mut self) {
+        let seen = self.subscribers.iter().filter_map(|&seen| seen).min()
+            .unwrap_or(self.dropped + self.edits.len());
+
+        self.edits.drain(.. seen - self.dropped);
+        self.points.drain(.. seen - self.dropped);
+        self.dropped = seen;
+    }
+}
+
+/// The edit turning the elements of `old` into those of `new`, covering everything between the pieces they share at both ends.
+///
+/// Takes `O(p)` time.
+pub(crate) fn difference(old: &Tree, new: &Tree) -> Edit {
+    let runs = |tree: &Tree| tree.iter().map(|piece| (piece.buffer, piece.start, piece.length)).collect::<Vec<(Buffer, usize, usize)>>();
+    let (old_runs, new_runs) = (runs(old), runs(new));
+
+    let prefix = common(old_runs.iter().cloned(), new_runs.iter().cloned(), false);
+    let suffix = common(old_runs.iter().rev().cloned(), new_runs.iter().rev().cloned(), true)
+        .min(old.len().min(new.len()) - prefix);
+
+    Edit {
+        start: prefix,
+        removed_len: old.len() - prefix - suffix,
+        inserted_len: new.len() - prefix - suffix,
+    }
+}
+
+/// The number of elements stored at the same places at the start of both lists of pieces, or at the end if `backwards`.
+fn common<I>(mut a: I, mut b: I, backwards: bool) -> usize where I: Iterator<Item=(Buffer, usize, usize)> {
+    let mut count = 0;
+    let (mut x, mut y) = (a.next(), b.next());
+
+    while let (Some((x_buffer, x_start, x_length)), Some((y_buffer, y_start, y_length))) = (x, y) {
+        let aligned = if backwards {
+            x_start + x_length == y_start + y_length
+        } else {
+            x_start == y_start
+        };
+
+        if x_buffer != y_buffer || !aligned {
+            break;
+        }
+
+        // Skip the elements both pieces share, and move on from the one that ends.
+        let n = x_length.min(y_length);
+        count += n;
+
+        let skip = |start: usize, length: usize| if backwards { (start, length - n) } else { (start + n, length - n) };
+        let (x_start, x_length) = skip(x_start, x_length);
+        let (y_start, y_length) = skip(y_start, y_length);
+
+        x = if x_length == 0 { a.next() } else { Some((x_buffer, x_start, x_length)) };
+        y = if y_length == 0 { b.next() } else { Some((y_buffer, y_start, y_length)) };
+    }
+
+    count
+}
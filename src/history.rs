@@ -0,0 +1,120 @@
+//! Undo tree for a `PieceTable`.
+//!
+//! Every change creates a revision, a child of the revision it was made on top of, so no state is lost when editing after an undo.
+//! Revisions are numbered in the order they are created, with `0` being the state before any change.
+//! A revision stores a copy of the piece tree, which shares all unchanged nodes with the live table, so recording one is cheap.
+
+use tree::Tree;
+
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct State {
+    pub pieces: Tree,
+    pub length: usize,
+}
+
+#[derive(Debug, Clone, Hash)]
+struct Revision {
+    state: State,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    // The child most recently left by undo, which redo returns to.
+    redo: Option<usize>,
+}
+
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct History {
+    revisions: Vec<Revision>,
+    current: usize,
+}
+
+impl History {
+    /// Start a history whose root revision is `state`.
+    pub fn new(state: State) -> History {
+        History {
+            revisions: vec![Revision {
+                state: state,
+                parent: None,
+                children: Vec::new(),
+                redo: None,
+            }],
+            current: 0,
+        }
+    }
+
+    /// The revision the table is at.
+    /// Its stored state is only brought up to date when it is left, as the live table is authoritative until then.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn len(&self) -> usize {
+        self.revisions.len()
+    }
+
+    pub fn parent(&self, id: usize) -> Option<usize> {
+        self.revisions.get(id).and_then(|revision| revision.parent)
+    }
+
+    pub fn children(&self, id: usize) -> &[usize] {
+        self.revisions.get(id).map_or(&[], |revision| &revision.children[..])
+    }
+
+    /// The states of all revisions, where that of the current one may be out of date.
+    pub fn states(&self) -> impl Iterator<Item=&State> {
+        self.revisions.iter().map(|revision| &revision.state)
+    }
+
+    pub fn states_mut(&mut self) -> impl Iterator<Item=&mut State> {
+        self.revisions.iter_mut().map(|revision| &mut revision.state)
+    }
+
+    /// Start a new revision on top of the current one, which had `state`.
+    pub fn record(&mut self, state: State) {
+        let id = self.revisions.len();
+        let parent = self.current;
+
+        self.revisions[parent].children.push(id);
+        self.revisions.push(Revision {
+            state: state.clone(),
+            parent: Some(parent),
+            children: Vec::new(),
+            redo: None,
+        });
+
+        self.leave(state, id);
+    }
+
+    /// Move from the current revision, which has `state`, to its parent.
+    pub fn undo(&mut self, state: State) -> Option<State> {
+        self.revisions[self.current].parent.map(|parent| {
+            self.revisions[parent].redo = Some(self.current);
+            self.leave(state, parent)
+        })
+    }
+
+    /// Move from the current revision, which has `state`, to the child it was last undone from.
+    /// If it has never been undone from, the newest child is used.
+    pub fn redo(&mut self, state: State) -> Option<State> {
+        let child = {
+            let revision = &self.revisions[self.current];
+            revision.redo.or(revision.children.last().cloned())
+        };
+
+        child.map(|child| self.leave(state, child))
+    }
+
+    /// Move from the current revision, which has `state`, to revision `id`.
+    pub fn goto(&mut self, state: State, id: usize) -> Option<State> {
+        if id < self.revisions.len() {
+            Some(self.leave(state, id))
+        } else {
+            None
+        }
+    }
+
+    fn leave(&mut self, state: State, id: usize) -> State {
+        self.revisions[self.current].state = state;
+        self.current = id;
+        self.revisions[id].state.clone()
+    }
+}
@@ -1,18 +1,58 @@
 //! Implementation of a piece table based on a vector.
 //! A piece table provides efficient methods for inserting and removing elements sequentially, intended for use as the underlying data structure in a text editor.
 //!
-//! The piece table stores a read only reference to a source (if one is provided) and stores inserted elements in an append-only vector.
+//! The piece table stores a read only source (if one is provided) and stores inserted elements in an append-only vector.
+//! The source is borrowed by default, but any type that can be viewed as a slice can be used, so a table can also own its source.
 //! A table of pieces pointing either to the source or add-buffer is maintained, and these pieces are manipulated when inserting and removing text.
+//! The pieces are kept in a balanced tree where every node caches the length of its subtree, so an index can be located in `O(log p)` time.
 //! Asymptotics in the following are based on `p`, the number of pieces, where `p` should be strictly smaller than the number of elements when used as intended.
 
 #![feature(collections_bound)]
 
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+
+use std::io::{self, IoSlice, Write};
 use std::iter::Iterator;
-use std::ops::Index;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Index, RangeBounds};
 use std::collections::Bound;
 
+mod adds;
+mod anchors;
+mod cursor;
+mod decorations;
+mod edits;
+mod history;
+mod lines;
+mod lsp;
+mod marks;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod snapshot;
+mod text;
+mod tree;
+
+pub use anchors::{Anchor, Gravity};
+pub use cursor::{Cursor, CursorMut};
+pub use decorations::{Decoration, Decorations, Intersecting};
+pub use edits::{Edit, InputEdit, Point, Subscription};
+pub use lines::{LineBreak, Lines};
+pub use lsp::{Position, PositionEncoding, TextEdit};
+#[cfg(feature = "mmap")]
+pub use mmap::MmapSource;
+pub use snapshot::Snapshot;
+pub use text::{CharIndices, Chars, TextTable};
+
 use Buffer::*;
 use Location::*;
+use adds::Adds;
+use anchors::Anchors;
+use edits::EditLog;
+use history::{History, State};
+use lines::LineIndex;
+use tree::Tree;
 
 #[derive(Debug, PartialEq, Clone, Copy, Hash)]
 enum Buffer {
@@ -33,32 +73,57 @@ struct Piece {
     start: usize,
     length: usize,
     buffer: Buffer,
+    // The number of line breaks in the piece, if the line index is enabled.
+    breaks: usize,
 }
 
 /// The `PieceTable` type with all relevant methods.
-#[derive(Debug, Clone, Hash)]
-pub struct PieceTable<'a, T: 'a> {
-    original: &'a [T],
-    adds: Vec<T>,
-    pieces: Vec<Piece>,
+///
+/// `S` is the type of the source, which is a borrowed slice unless another one is given with `source`.
+///
+/// Cloning a table shares the elements and the pieces with the clone instead of copying them, and both can be edited on their own afterwards.
+#[derive(Debug, Hash)]
+pub struct PieceTable<'a, T: 'a, S = &'a [T]> {
+    original: Option<S>,
+    adds: Adds<T>,
+    pieces: Tree,
     last_idx: usize,
     length: usize,
     reusable_insert: Option<(usize, bool)>,
     reusable_remove: Option<Location>,
+    reusable_remove_forward: Option<Location>,
+    history: Option<History>,
+    transactions: Vec<State>,
+    lines: Option<LineIndex<T>>,
+    anchors: Anchors,
+    edits: EditLog,
+    marker: PhantomData<&'a T>,
 }
 
 /// Struct for iterating the elements of a `PieceTable`.
 pub struct Iter<'a, T: 'a>
 {
-    table: &'a PieceTable<'a, T>,
-    piece_idx: usize,
-    it: std::slice::Iter<'a, T>,
+    chunks: Chunks<'a, T>,
+    front: std::slice::Iter<'a, T>,
+    back: std::slice::Iter<'a, T>,
 }
 
 /// Struct for iterating a range of elements in a `PieceTable`.
 pub struct Range<'a, T: 'a> {
     iter: Iter<'a, T>,
-    idx: usize,
+}
+
+/// Struct for iterating the elements removed from a `PieceTable` by `drain`.
+pub struct Drain<'a, T: 'a> {
+    iter: Iter<'a, T>,
+}
+
+/// Struct for iterating the contiguous slices of elements in a `PieceTable`, one per piece or part of a piece.
+pub struct Chunks<'a, T: 'a> {
+    original: &'a [T],
+    adds: &'a Adds<T>,
+    pieces: Tree,
+    from: usize,
     to: usize,
 }
 
@@ -75,20 +140,31 @@ impl<'a, T: 'a> PieceTable<'a, T> {
         Default::default()
     }
 
-    /// Constructs a new, empty `PieceTable<T>` with the specified capacity for elements and pieces.
+    /// Constructs a new, empty `PieceTable<T>` with the specified capacity for elements.
     /// Sequential insertion of `data_capacity` elements will be possible without reallocation.
-    /// Scattered operations results in pieces being added; the created piece table will be able to store `piece_capacity` of these before reallocating.
-    pub fn with_capacity(data_capacity: usize, piece_capacity: usize) -> PieceTable<'a, T> {
+    ///
+    /// `piece_capacity` has no effect: pieces are nodes of a tree and allocated one at a time as they are created, so no capacity is kept for them.
+    pub fn with_capacity(data_capacity: usize, _piece_capacity: usize) -> PieceTable<'a, T> {
         PieceTable {
-            original: &[],
-            adds: Vec::with_capacity(data_capacity),
-            pieces: Vec::with_capacity(piece_capacity),
+            original: None,
+            adds: Adds::with_capacity(data_capacity),
+            pieces: Tree::new(),
             last_idx: 0,
             length: 0,
             reusable_insert: None,
             reusable_remove: None,
+            reusable_remove_forward: None,
+            history: None,
+            transactions: Vec::new(),
+            lines: None,
+            anchors: Anchors::new(),
+            edits: EditLog::new(),
+            marker: PhantomData,
         }
     }
+}
+
+impl<'a, T: 'a, S: 'a + AsRef<[T]>> PieceTable<'a, T, S> {
 
     /// Assign a read-only source to an existing `PieceTable`.
     ///
@@ -98,23 +174,308 @@ impl<'a, T: 'a> PieceTable<'a, T> {
     /// let src: Vec<i32> = (0..100).collect();
     /// let table = PieceTable::new().src(&src);
     /// ```
-    pub fn src(mut self, src: &'a [T]) -> PieceTable<'a, T> {
-        let mut pieces = Vec::new();
-        if src.len() > 0 {
-            pieces.push(Piece {
-                start: 0,
-                length: src.len(),
-                buffer: Original,
-            });
+    pub fn src(self, src: &'a [T]) -> PieceTable<'a, T> {
+        self.source(src)
+    }
+
+    /// Assign a read-only source of any type that can be viewed as a slice, such as `Vec<T>`, `Box<[T]>`, `Arc<[T]>` or `Cow<[T]>`.
+    ///
+    /// With an owned source the table does not borrow anything, so it can be stored in long-lived structs, returned from functions and sent to other threads.
+    /// Anchors created before keep working, but are detached from their elements as if the table had been cleared,
+    /// so they end up at the start, except those at the end with `Gravity::Right`, which stay at the end.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// use std::sync::Arc;
+    ///
+    /// fn load() -> PieceTable<'static, i32, Arc<[i32]>> {
+    ///     let src: Vec<i32> = (0..10).collect();
+    ///     PieceTable::new().source(src.into())
+    /// }
+    ///
+    /// let mut table = load();
+    /// table.remove(0);
+    /// assert_eq!(9, table.len());
+    /// ```
+    pub fn source<U>(self, src: U) -> PieceTable<'a, T, U> where U: 'a + AsRef<[T]> {
+        let length = src.as_ref().len();
+        let edit = Edit {
+            start: 0,
+            removed_len: self.length,
+            inserted_len: length,
+        };
+        let old_end = self.before_edit().map(|pieces| self.point_in(&pieces, edit.removed_len));
+
+        let lines = self.lines.map(|mut lines| {
+            lines.index_original(src.as_ref());
+            lines
+        });
+
+        let mut anchors = self.anchors;
+        anchors.clear(length);
+
+        let mut table = PieceTable {
+            original: Some(src),
+            adds: self.adds,
+            pieces: Tree::new(),
+            last_idx: 0,
+            length: length,
+            reusable_insert: None,
+            reusable_remove: None,
+            reusable_remove_forward: None,
+            history: None,
+            transactions: Vec::new(),
+            lines: lines,
+            anchors: anchors,
+            edits: self.edits,
+            marker: PhantomData,
+        };
+
+        if length > 0 {
+            let piece = table.piece(Original, 0, length);
+            table.pieces.push(piece);
         }
 
-        self.original = src;
-        self.pieces = pieces;
-        self.length = src.len();
+        if self.history.is_some() {
+            table.history = Some(History::new(table.state()));
+        }
+
+        table.push_edit_ending(edit, old_end);
+
+        table
+    }
+
+    /// Start recording undo history for an existing `PieceTable`.
+    ///
+    /// Every call to `insert`, `remove`, `push` or `extend` afterwards creates a new revision, which can be undone.
+    /// Recording a revision takes `O(log p)` time and space, as the piece tree is shared with the previous revision.
+    ///
+    /// Revisions form a tree: editing after an undo starts a new branch, and the old branch remains reachable.
+    /// They are numbered in the order they were created, starting with `0` for the state when history was enabled.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..10).collect();
+    /// let mut table = PieceTable::new().src(&src).history();
+    /// table.remove(3);
+    /// table.undo();
+    /// assert_eq!(src.iter().collect::<Vec<&i32>>(), table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn history(mut self) -> PieceTable<'a, T, S> {
+        if self.history.is_none() {
+            self.history = Some(History::new(self.state()));
+        }
 
         self
     }
 
+    /// Revert to the parent of the current revision, returning `false` if there is nothing to undo.
+    ///
+    /// Takes `O(1)` time; only the piece tree is swapped, no elements are copied.
+    /// Nothing is undone while a transaction is open.
+    pub fn undo(&mut self) -> bool {
+        self.navigate(|history, state| history.undo(state))
+    }
+
+    /// Reapply the revision most recently undone from the current one, returning `false` if there is nothing to redo.
+    /// If no revision was undone from here yet, the newest child revision is used.
+    ///
+    /// Nothing is redone while a transaction is open.
+    pub fn redo(&mut self) -> bool {
+        self.navigate(|history, state| history.redo(state))
+    }
+
+    /// The number of the current revision, or `0` if history is not enabled.
+    pub fn revision(&self) -> usize {
+        self.history.as_ref().map_or(0, |history| history.current())
+    }
+
+    /// The number of revisions recorded, including the initial one.
+    pub fn revision_count(&self) -> usize {
+        self.history.as_ref().map_or(0, |history| history.len())
+    }
+
+    /// The revision that revision `id` was created on top of, if any.
+    pub fn revision_parent(&self, id: usize) -> Option<usize> {
+        self.history.as_ref().and_then(|history| history.parent(id))
+    }
+
+    /// The revisions created on top of revision `id`, oldest first.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let mut table = PieceTable::new().history();
+    /// table.push(1);
+    /// table.undo();
+    /// table.push(2);
+    /// assert_eq!(&[1, 2], table.revision_children(0));
+    /// ```
+    pub fn revision_children(&self, id: usize) -> &[usize] {
+        self.history.as_ref().map_or(&[], |history| history.children(id))
+    }
+
+    /// Check out revision `id`, returning `false` if it does not exist.
+    ///
+    /// Takes `O(1)` time; only the piece tree is swapped, no elements are copied.
+    /// Nothing is checked out while a transaction is open.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let mut table = PieceTable::new().history();
+    /// table.push(1);
+    /// table.undo();
+    /// table.push(2);
+    /// table.goto_revision(1);
+    /// assert_eq!(vec![&1], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn goto_revision(&mut self, id: usize) -> bool {
+        self.navigate(|history, state| history.goto(state, id))
+    }
+
+    /// Check out the revision created just before the current one, regardless of branches, like Vim's `g-`.
+    pub fn earlier(&mut self) -> bool {
+        match self.revision() {
+            0 => false,
+            id => self.goto_revision(id-1),
+        }
+    }
+
+    /// Check out the revision created just after the current one, regardless of branches, like Vim's `g+`.
+    pub fn later(&mut self) -> bool {
+        let id = self.revision();
+        self.goto_revision(id+1)
+    }
+
+    fn navigate<F>(&mut self, f: F) -> bool where F: FnOnce(&mut History, State) -> Option<State> {
+        if !self.transactions.is_empty() {
+            return false;
+        }
+
+        let current = self.state();
+        match self.history.as_mut().and_then(|history| f(history, current)) {
+            Some(state) => {
+                self.restore(state);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn state(&self) -> State {
+        State {
+            pieces: self.pieces.clone(),
+            length: self.length,
+        }
+    }
+
+    fn restore(&mut self, state: State) {
+        let old = mem::replace(&mut self.pieces, state.pieces);
+        if self.edits.is_active() {
+            let edit = edits::difference(&old, &self.pieces);
+            let before = self.before_edit().map(|_| old);
+            self.push_edit(edit, before);
+        }
+
+        self.length = state.length;
+        self.reusable_insert = None;
+        self.reusable_remove = None;
+        self.reusable_remove_forward = None;
+
+        if !self.anchors.is_empty() {
+            self.anchors.restore(&self.pieces, self.length);
+        }
+    }
+
+    fn record(&mut self) {
+        if let Some(ref mut history) = self.history {
+            if self.transactions.is_empty() {
+                history.record(State {
+                    pieces: self.pieces.clone(),
+                    length: self.length,
+                });
+            }
+        }
+    }
+
+    /// Whether pieces not in the table, anchors or the points of edits may still refer to the end of `adds`, so it must not shrink.
+    fn adds_shared(&self) -> bool {
+        self.history.is_some() || !self.transactions.is_empty() || !self.anchors.is_empty() || self.edits.is_active()
+    }
+
+    /// Open a transaction, grouping all following edits into a single revision until `commit` is called.
+    ///
+    /// Transactions can be nested, in which case only the outermost `commit` records a revision.
+    /// Use `transact` to also have the transaction rolled back on panics.
+    pub fn begin_transaction(&mut self) {
+        let state = self.state();
+        self.transactions.push(state);
+    }
+
+    /// Close the innermost open transaction, keeping its edits.
+    /// Returns `false` if no transaction was open.
+    pub fn commit(&mut self) -> bool {
+        match self.transactions.pop() {
+            Some(start) => {
+                if self.transactions.is_empty() && !start.pieces.ptr_eq(&self.pieces) {
+                    if let Some(ref mut history) = self.history {
+                        history.record(start);
+                    }
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Close the innermost open transaction, restoring the table to its state when the transaction began.
+    /// Returns `false` if no transaction was open.
+    pub fn rollback(&mut self) -> bool {
+        match self.transactions.pop() {
+            Some(start) => {
+                self.restore(start);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Run `f` inside a transaction, committing it when `f` returns.
+    /// If `f` panics, the transaction is rolled back before the panic continues.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let mut table = PieceTable::new().history();
+    /// table.transact(|t| {
+    ///     t.push(1);
+    ///     t.push(2);
+    ///     t.insert(0, 3);
+    /// });
+    /// assert_eq!(vec![&3, &1, &2], table.iter().collect::<Vec<&i32>>());
+    /// table.undo();
+    /// assert!(table.is_empty());
+    /// ```
+    pub fn transact<F, R>(&mut self, f: F) -> R where F: FnOnce(&mut PieceTable<'a, T, S>) -> R {
+        self.begin_transaction();
+
+        let mut guard = TransactionGuard {
+            depth: self.transactions.len(),
+            table: self,
+        };
+
+        let result = f(guard.table);
+        while guard.table.transactions.len() >= guard.depth {
+            guard.table.commit();
+        }
+        guard.depth = 0;
+
+        result
+    }
+
     /// The number of elements stored in the piece table.
     pub fn len(&self) -> usize {
         self.length
@@ -130,9 +491,12 @@ impl<'a, T: 'a> PieceTable<'a, T> {
         self.adds.capacity()
     }
 
-    /// Returns the number of pieces (created by scattered operations), the piece table can hold without reallocating.
+    /// Returns the current number of pieces, the same as `piece_count`.
+    ///
+    /// Pieces are nodes of a tree and allocated one at a time as they are created, so no capacity is kept for them.
+    #[deprecated(note = "no capacity is kept for pieces, use `piece_count` for the number of pieces")]
     pub fn capacity_pieces(&self) -> usize {
-        self.pieces.capacity()
+        self.pieces.count()
     }
 
     /// Reserves capacity for at least `additional` more elements to be inserted.
@@ -141,51 +505,212 @@ impl<'a, T: 'a> PieceTable<'a, T> {
         self.adds.reserve(additional);
     }
 
-    /// Reserves capacity for at least `additional` more pieces to be created.
-    /// The collection may reserve more space to avoid frequent reallocations.
-    pub fn reserve_piece(&mut self, additional: usize) {
-        self.pieces.reserve(additional);
+    /// Does nothing.
+    ///
+    /// Pieces are nodes of a tree and allocated one at a time as they are created, so no capacity is kept for them.
+    #[deprecated(note = "no capacity is kept for pieces, so there is nothing to reserve")]
+    pub fn reserve_piece(&mut self, _additional: usize) {
+    }
+
+    /// The number of elements in the add buffer that `compact` would free, as no piece of the table, its undo history or its open transactions refers to them.
+    ///
+    /// Takes `O(p log p)` time for the `p` pieces of all revisions, counting pieces shared between revisions once.
+    pub fn reclaimable(&self) -> usize {
+        self.adds.len() - self.kept_adds().iter().map(|&(start, end)| end - start).sum::<usize>()
+    }
+
+    /// Free the elements of the add buffer that no piece refers to, by copying the others into a new add buffer,
+    /// and merge neighbouring pieces that refer to consecutive elements afterwards.
+    /// Returns the number of elements freed.
+    ///
+    /// Undo history, open transactions, anchors and decorations keep working, and revisions keep sharing their unchanged pieces.
+    /// Clones and snapshots keep the old add buffer alive until they are dropped.
+    ///
+    /// Takes `O(p log p)` time for the `p` pieces of all revisions, plus the time to copy the elements kept.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let mut table = PieceTable::new();
+    /// table.insert_slice(0, &[1, 2, 3, 4, 5]);
+    /// table.insert_slice(5, &[6, 7, 8]);
+    /// table.remove_range(1..7);
+    /// assert_eq!(6, table.reclaimable());
+    /// assert_eq!(6, table.compact());
+    /// assert_eq!(0, table.reclaimable());
+    /// assert_eq!(vec![&1, &8], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn compact(&mut self) -> usize where T: Clone {
+        let kept = self.kept_adds();
+
+        // Copy the kept ranges next to each other, noting where each one starts now.
+        let mut adds = Adds::with_capacity(kept.iter().map(|&(start, end)| end - start).sum());
+        let mut starts = Vec::with_capacity(kept.len());
+        for &(start, end) in &kept {
+            starts.push(adds.len());
+
+            let mut pos = start;
+            while pos < end {
+                let elements = self.adds.slice_from(pos, end);
+                adds.extend_from_slice(elements);
+                pos += elements.len();
+            }
+        }
+
+        let remap = |pos: usize| {
+            let i = kept.partition_point(|&(start, _)| start <= pos);
+            match i.checked_sub(1).map(|i| (i, kept[i])) {
+                Some((i, (start, end))) if pos < end => Some(starts[i] + pos - start),
+                _ => None,
+            }
+        };
+
+        {
+            let trees = std::iter::once(&mut self.pieces)
+                .chain(self.transactions.iter_mut().map(|state| &mut state.pieces))
+                .chain(self.history.iter_mut().flat_map(|history| history.states_mut()).map(|state| &mut state.pieces));
+
+            Tree::map_shared(trees, |piece| match piece.buffer {
+                // Empty pieces keep nothing, and read nothing wherever they start.
+                Add if piece.length == 0 => Piece {
+                    start: 0,
+                    ..*piece
+                },
+                Add => Piece {
+                    start: remap(piece.start).expect("live add piece was kept"),
+                    ..*piece
+                },
+                Original => *piece,
+            });
+        }
+
+        self.coalesce();
+        self.anchors.remap(remap);
+
+        let freed = self.adds.len() - adds.len();
+        self.adds = adds;
+        if let Some(ref mut lines) = self.lines {
+            lines.clear_adds();
+        }
+        self.index_adds(0);
+
+        freed
+    }
+
+    /// Merge all neighbouring pieces that refer to consecutive elements of the same buffer, returning the number of pieces merged away.
+    ///
+    /// Edits already merge the pieces next to the elements they change, and `compact` merges all pieces, so this rarely finds anything to merge.
+    /// Takes `O(p log p)` time.
+    pub fn defragment(&mut self) -> usize {
+        let count = self.pieces.count();
+        self.coalesce();
+        count - self.pieces.count()
+    }
+
+    /// The number of pieces, which the time taken by many operations depends on.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..10).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.insert(5, 42);
+    /// assert_eq!(3, table.piece_count());
+    /// table.remove(5);
+    /// assert_eq!(1, table.piece_count());
+    /// ```
+    pub fn piece_count(&self) -> usize {
+        self.pieces.count()
+    }
+
+    /// The ranges of the add buffer referred to by any piece of the table, its undo history or its open transactions, sorted and merged where they touch.
+    fn kept_adds(&self) -> Vec<(usize, usize)> {
+        let trees = std::iter::once(&self.pieces)
+            .chain(self.transactions.iter().map(|state| &state.pieces))
+            .chain(self.history.iter().flat_map(|history| history.states()).map(|state| &state.pieces));
+
+        let mut ranges = Vec::new();
+        Tree::visit_shared(trees, |piece| {
+            if piece.buffer == Add && piece.length > 0 {
+                ranges.push((piece.start, piece.start + piece.length));
+            }
+        });
+        ranges.sort();
+
+        let mut kept: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in ranges {
+            match kept.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => kept.push((start, end)),
+            }
+        }
+        kept
+    }
+
+    /// Merge all neighbouring pieces referring to consecutive elements of the same buffer.
+    fn coalesce(&mut self) {
+        let mut pieces = Tree::new();
+        for piece in self.pieces.iter() {
+            let consecutive = pieces.last().is_some_and(|last| last.buffer == piece.buffer && last.start + last.length == piece.start);
+
+            if consecutive {
+                let last = pieces.count() - 1;
+                pieces.update(last, |last| {
+                    last.length += piece.length;
+                    last.breaks += piece.breaks;
+                });
+            } else {
+                pieces.push(*piece);
+            }
+        }
+
+        // Revisions identical to the table stay identical, so committing an unchanged transaction still records nothing.
+        for state in self.transactions.iter_mut().chain(self.history.iter_mut().flat_map(|history| history.states_mut())) {
+            if state.pieces.ptr_eq(&self.pieces) {
+                state.pieces = pieces.clone();
+            }
+        }
+        self.pieces = pieces;
+
+        self.reusable_insert = None;
+        self.reusable_remove = None;
+        self.reusable_remove_forward = None;
     }
 
     /// Clears the piece table, removing all elements.
-    /// Also removes reference to any given `src`.
+    /// Also removes reference to any given `src`, forgets the undo history and abandons open transactions.
     pub fn clear(&mut self) {
-        self.original = &[];
+        let edit = Edit {
+            start: 0,
+            removed_len: self.length,
+            inserted_len: 0,
+        };
+        let old_end = self.before_edit().map(|pieces| self.point_in(&pieces, edit.removed_len));
+
+        self.transactions.clear();
+        self.original = None;
         self.adds.clear();
         self.pieces.clear();
+        if let Some(ref mut lines) = self.lines {
+            lines.clear();
+        }
+        self.anchors.clear(0);
         self.length = 0;
-    }
-
-    fn make_iter(&'a self, idx: usize) -> Iter<'a, T> {
-        let (piece_idx, norm_idx) = match self.idx_to_location(idx) {
-            PieceHead(piece_idx) => (piece_idx, 0),
-            PieceMid(piece_idx, norm_idx) |
-            PieceTail(piece_idx, norm_idx) => (piece_idx, norm_idx),
-            EOF => {
-                // Need an iterator that just closes.
-                let it = self.adds[(0 .. 0)].iter();
-                return Iter {
-                    table: &self,
-                    piece_idx: self.pieces.len(),
-                    it: it,
-                }
-            },
-        };
-
-        let piece = self.pieces[piece_idx];
-        let buf = self.get_buffer(&piece);
-        let it = buf[(piece.start + norm_idx .. piece.start + piece.length)].iter();
+        self.reusable_insert = None;
+        self.reusable_remove = None;
+        self.reusable_remove_forward = None;
 
-        Iter {
-            table: &self,
-            piece_idx: piece_idx,
-            it: it,
+        if self.history.is_some() {
+            self.history = Some(History::new(self.state()));
         }
+
+        self.push_edit_ending(edit, old_end);
     }
 
     /// Return an iterator over all elements of the `PieceTable`.
     ///
     /// Advancing the iterator takes constant time.
+    /// It can also be consumed from the back, and `nth`, `nth_back` and `skip` jump over whole pieces in `O(log p)` time.
     ///
     /// # Example
     /// ```
@@ -195,11 +720,12 @@ impl<'a, T: 'a> PieceTable<'a, T> {
     /// assert_eq!(5050, table.iter().fold(0, |acc, &x| acc + x));
     /// ```
     pub fn iter(&'a self) -> Iter<'a, T> {
-        self.make_iter(0)
+        Iter::new(self.chunks())
     }
 
     /// Return an iterator over the bound range in the `PieceTable`.
-    /// Constructing the iterator takes `O(p)` time, but consuming it is constant time per element.
+    /// Constructing the iterator takes `O(log p)` time, but consuming it is constant time per element.
+    /// Like `iter`, it can be consumed from both ends.
     ///
     /// # Example
     /// ```
@@ -211,293 +737,1291 @@ impl<'a, T: 'a> PieceTable<'a, T> {
     /// assert_eq!(vec![&55, &56, &57], table.range(Included(55), Excluded(58)).collect::<Vec<&i32>>());
     /// ```
     pub fn range(&'a self, min: Bound<usize>, max: Bound<usize>) -> Range<'a, T> {
-        let from = match min {
-            Bound::Included(x) => x,
-            Bound::Excluded(x) => x+1,
-            Bound::Unbounded => 0,
-        };
-
-        let to = match max {
-            Bound::Included(x) => x+1,
-            Bound::Excluded(x) => x,
-            Bound::Unbounded => self.length,
-        };
-
-
-        let iter = self.make_iter(from);
+        let (from, to) = self.bounds((min, max));
+        let to = std::cmp::min(to, self.length);
+        let from = std::cmp::min(from, to);
 
         Range {
-            iter: iter,
-            idx: from,
-            to: to,
+            iter: Iter::new(self.chunks_in(from .. to)),
         }
     }
 
-    /// Insert an element at `idx`.
-    ///
-    /// `O(p)` time, but sequential inserts afterwards take `O(1) `time.
+    /// Return an iterator over the elements of the `PieceTable` as slices, one for every piece.
+    /// Elements inserted after a `snapshot` was taken are stored apart from those inserted before, so a piece holding both yields a slice for each.
     ///
-    /// # Panics
-    /// Panics if not `idx <= len`.
+    /// Slices can be handed to code working on slices, such as searching or hashing, without copying.
+    /// Advancing the iterator takes `O(log p)` time.
     ///
     /// # Example
     /// ```
     /// use piecetable::PieceTable;
-    /// let src: Vec<i32> = (0..100).collect();
+    /// let src: Vec<i32> = (0..5).collect();
     /// let mut table = PieceTable::new().src(&src);
-    /// table.insert(11, 42); // takes `O(p)` time
-    /// table.insert(12, 42); // constant time
-    /// table.insert(13, 42); // constant time
-    /// table.insert(27, 42); // `O(p)` time
-    /// table.insert(28, 42); // constant time
+    /// table.insert(2, 7);
+    /// assert_eq!(vec![&[0, 1][..], &[7], &[2, 3, 4]], table.chunks().collect::<Vec<&[i32]>>());
     /// ```
-    pub fn insert(&mut self, idx: usize, item: T) {
-        assert!(idx <= self.length);
-
-        match self.reusable_insert {
-            Some((piece_idx, ref mut inserted))
-                if (idx == self.last_idx+1 && *inserted)
-                || (idx == self.last_idx && !*inserted) => {
-                    let piece = &mut self.pieces[piece_idx];
-                    self.adds.push(item);
-                    piece.length += 1;
-                    *inserted = true;
-                }
-            _ => self.raw_insert(idx, item),
-        }
-
-        self.reusable_remove = None;
-        self.last_idx = idx;
-        self.length += 1;
-    }
-
-    fn raw_insert(&mut self, idx: usize, item: T) {
-        let item_idx = self.adds.len();
-        self.adds.push(item);
-
-        match self.idx_to_location(idx) {
-            PieceHead(piece_idx) => {
-                self.pieces.insert(piece_idx, Piece {
-                    start: item_idx,
-                    length: 1,
-                    buffer: Add,
-                });
-
-                self.reusable_insert = Some((piece_idx, true));
-            },
-            PieceMid(piece_idx, norm_idx) | PieceTail(piece_idx, norm_idx) => {
-                let orig = self.pieces[piece_idx].clone();
-                self.pieces[piece_idx].length = norm_idx;
-
-                push_all_at(&mut self.pieces, piece_idx+1, &vec![
-                    Piece {
-                        start: item_idx,
-                        length: 1,
-                        buffer: Add,
-                    },
-                    Piece {
-                        start: orig.start + norm_idx,
-                        length: orig.length - norm_idx,
-                        buffer: orig.buffer,
-                    }]);
-
-                self.reusable_insert = Some((piece_idx+1, true));
-            },
-            EOF => {
-                let piece_idx = self.pieces.len();
-
-                self.pieces.push(Piece {
-                    start: item_idx,
-                    length: 1,
-                    buffer: Add,
-                });
-
-                self.reusable_insert = Some((piece_idx, true));
-            }
-        }
+    pub fn chunks(&'a self) -> Chunks<'a, T> {
+        self.chunks_in(..)
     }
 
-    /// Remove the element at the given index.
+    /// Return an iterator over the elements in `range` as slices, clipping the first and last piece to the range.
     ///
-    /// `O(p)` operation initially, but removing sequentially backwards afterwards is `O(1)`.
+    /// Constructing the iterator takes `O(log p)` time.
     ///
     /// # Panics
-    /// Panics if not `idx < len`.
+    /// Panics if the range is out of bounds.
     ///
     /// # Example
     /// ```
     /// use piecetable::PieceTable;
-    /// let src: Vec<i32> = (0..10).collect();
+    /// let src: Vec<i32> = (0..5).collect();
     /// let mut table = PieceTable::new().src(&src);
-    /// table.remove(5); // `O(p)`
-    /// table.remove(4); // `O(1)`
-    /// table.remove(3); // `O(1)`
-    /// table.remove(6); // `O(p)`
-    /// assert_eq!(vec![&0, &1, &2, &6, &7, &8], table.iter().collect::<Vec<&i32>>());
+    /// table.insert(2, 7);
+    /// assert_eq!(vec![&[1][..], &[7], &[2, 3]], table.chunks_in(1..5).collect::<Vec<&[i32]>>());
     /// ```
-    pub fn remove(&mut self, idx: usize) {
-        assert!(idx < self.length);
-        let remove: Option<usize>;
+    pub fn chunks_in<R>(&'a self, range: R) -> Chunks<'a, T> where R: RangeBounds<usize> {
+        let (from, to) = self.bounds(range);
+        assert!(from <= to && to <= self.length);
+
+        Chunks::new(self.original_buffer(), &self.adds, self.pieces.clone(), from, to)
+    }
+
+    fn bounds<R>(&self, range: R) -> (usize, usize) where R: RangeBounds<usize> {
+        bounds(range, self.length)
+    }
+
+    /// Take a read-only snapshot of the elements, which can be read on other threads while the table is edited.
+    ///
+    /// The snapshot shares the elements with the table instead of copying them.
+    /// Taking it takes `O(c)` time for the `c` chunks of the add buffer, plus the time to clone the source, which is cheap for a borrowed slice, an `Arc<[T]>` or a `MmapSource`.
+    /// The table keeps appending to the chunks it shares with snapshots, so `c` is `O(log n)` for `n` inserted elements however many snapshots are taken,
+    /// plus one for every clone that inserted elements before the table did.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// let src: Arc<[u8]> = b"hello".to_vec().into();
+    /// let mut table = PieceTable::new().source(src);
+    /// table.insert_slice(5, b" world");
+    ///
+    /// let snapshot = table.snapshot();
+    /// let saving = thread::spawn(move || snapshot.iter().cloned().collect::<Vec<u8>>());
+    /// table.remove_range(0..6);
+    ///
+    /// assert_eq!(b"hello world".to_vec(), saving.join().unwrap());
+    /// assert_eq!(b"world".to_vec(), table.iter().cloned().collect::<Vec<u8>>());
+    /// ```
+    pub fn snapshot(&self) -> Snapshot<'a, T, S> where S: Clone {
+        Snapshot::new(self.original.clone(), self.adds.clone(), self.pieces.clone(), self.length)
+    }
+
+    /// Return a cursor pointing at the element at `idx`, or the end of the table if `idx == len`.
+    ///
+    /// Placing the cursor takes `O(log p)` time.
+    ///
+    /// # Panics
+    /// Panics if not `idx <= len`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..10).collect();
+    /// let table = PieceTable::new().src(&src);
+    /// let mut cursor = table.cursor(4);
+    /// cursor.move_left();
+    /// assert_eq!(Some(&3), cursor.get());
+    /// ```
+    pub fn cursor(&'a self, idx: usize) -> Cursor<'a, T, S> {
+        Cursor::new(self, idx)
+    }
+
+    /// Return a cursor pointing at the element at `idx`, or the end of the table if `idx == len`, which can edit the table.
+    ///
+    /// Placing the cursor takes `O(log p)` time.
+    ///
+    /// # Panics
+    /// Panics if not `idx <= len`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..5).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// {
+    ///     let mut cursor = table.cursor_mut(2);
+    ///     cursor.insert(7);
+    ///     cursor.insert(8);
+    ///     cursor.delete_forward();
+    ///     cursor.seek(0);
+    ///     cursor.delete_forward();
+    /// }
+    /// assert_eq!(vec![&1, &7, &8, &3, &4], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn cursor_mut<'b>(&'b mut self, idx: usize) -> CursorMut<'b, 'a, T, S> {
+        CursorMut::new(self, idx)
+    }
+
+    /// Start indexing the line breaks of an existing `PieceTable`, which enables `line_count`, `line_to_offset`, `offset_to_line_col` and `lines`.
+    ///
+    /// The index is kept up to date by every edit, taking `O(log n)` extra time per changed piece, and one `usize` per line break of memory.
+    /// Enabling it counts the line breaks of all elements, including those of the revisions in the undo history and open transactions.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<char> = "one\ntwo\nthree".chars().collect();
+    /// let mut table = PieceTable::new().src(&src).line_index();
+    /// table.insert(4, '\n');
+    /// assert_eq!(4, table.line_count());
+    /// assert_eq!(5, table.line_to_offset(2));
+    /// assert_eq!((3, 2), table.offset_to_line_col(11));
+    /// ```
+    pub fn line_index(mut self) -> PieceTable<'a, T, S> where T: LineBreak {
+        if self.lines.is_none() {
+            let mut lines = LineIndex::new(T::is_line_break);
+            lines.index_original(self.original_buffer());
+            self.lines = Some(lines);
+            self.index_adds(0);
+
+            let lines = self.lines.as_ref().unwrap();
+            let trees = std::iter::once(&mut self.pieces)
+                .chain(self.transactions.iter_mut().map(|state| &mut state.pieces))
+                .chain(self.history.iter_mut().flat_map(|history| history.states_mut()).map(|state| &mut state.pieces));
+
+            Tree::map_shared(trees, |piece| Piece {
+                breaks: lines.count(piece.buffer, piece.start, piece.start + piece.length),
+                ..*piece
+            });
+        }
+
+        self
+    }
+
+    /// The number of lines, which is one more than the number of line breaks.
+    ///
+    /// # Panics
+    /// Panics if the line index is not enabled.
+    pub fn line_count(&self) -> usize {
+        self.line_index_ref();
+        self.pieces.breaks() + 1
+    }
+
+    /// The index of the first element of `line`, counting from zero, in `O(log p)` time.
+    ///
+    /// # Panics
+    /// Panics if the line index is not enabled or not `line < line_count`.
+    pub fn line_to_offset(&self, line: usize) -> usize {
+        assert!(line < self.line_count());
+        self.line_start_in(&self.pieces, line)
+    }
+
+    fn line_start_in(&self, pieces: &Tree, line: usize) -> usize {
+        let lines = self.line_index_ref();
+
+        if line == 0 {
+            return 0;
+        }
+
+        let (piece, offset, before) = pieces.locate_break(line).unwrap();
+        let position = lines.nth(piece.buffer, piece.start, line - before - 1);
+
+        offset + (position - piece.start) + 1
+    }
+
+    /// The line containing the element at `idx` and its column in that line, both counting from zero, in `O(log p)` time.
+    /// A line break belongs to the line it ends.
+    ///
+    /// # Panics
+    /// Panics if the line index is not enabled or not `idx <= len`.
+    pub fn offset_to_line_col(&self, idx: usize) -> (usize, usize) {
+        self.line_index_ref();
+        assert!(idx <= self.length);
+
+        let point = self.point_in(&self.pieces, idx);
+        (point.row, point.column)
+    }
+
+    fn point_in(&self, pieces: &Tree, idx: usize) -> Point {
+        let lines = self.line_index_ref();
+
+        let row = match pieces.locate_breaks(idx) {
+            Some((piece, offset, before)) => before + lines.count(piece.buffer, piece.start, piece.start + idx - offset),
+            None => pieces.breaks(),
+        };
+
+        Point {
+            row: row,
+            column: idx - self.line_start_in(pieces, row),
+        }
+    }
+
+    /// Return an iterator over the lines, each being a `Range` over its elements without the line break.
+    ///
+    /// Moving to the next line takes `O(log p)` time.
+    ///
+    /// # Panics
+    /// Panics if the line index is not enabled.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src = b"one\ntwo\n".to_vec();
+    /// let table = PieceTable::new().src(&src).line_index();
+    /// let lines = table.lines().map(|line| line.map(|&x| x).collect()).collect::<Vec<Vec<u8>>>();
+    /// assert_eq!(vec![b"one".to_vec(), b"two".to_vec(), vec![]], lines);
+    /// ```
+    pub fn lines(&'a self) -> Lines<'a, T, S> {
+        Lines::new(self)
+    }
+
+    /// Create an anchor at `idx`, which follows the element on the side given by `gravity` as the table is edited.
+    ///
+    /// Every edit afterwards takes `O(log a)` extra time for `a` anchors, plus `O(1)` for every anchor whose element it removes,
+    /// and undo, redo and rollback take `O(p log a)` extra time to find the elements again.
+    ///
+    /// # Panics
+    /// Panics if not `idx <= len`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::{Gravity, PieceTable};
+    /// let src = b"hello world".to_vec();
+    /// let mut table = PieceTable::new().src(&src);
+    /// let end = table.create_anchor(5, Gravity::Left);
+    /// let world = table.create_anchor(6, Gravity::Right);
+    /// table.insert_slice(5, b",");
+    /// table.insert_slice(0, b">> ");
+    /// assert_eq!(8, table.anchor_offset(end));
+    /// assert_eq!(10, table.anchor_offset(world));
+    /// table.remove_range(9..);
+    /// assert!(table.anchor_deleted(world));
+    /// assert_eq!(9, table.anchor_offset(world));
+    /// ```
+    pub fn create_anchor(&mut self, idx: usize, gravity: Gravity) -> Anchor {
+        assert!(idx <= self.length);
+
+        let element = match gravity {
+            Gravity::Left if idx > 0 => self.element(idx - 1),
+            Gravity::Right if idx < self.length => self.element(idx),
+            _ => None,
+        };
+
+        self.anchors.create(idx, gravity, element)
+    }
+
+    /// The offset of `anchor`, in `O(log a)` time.
+    /// If the element it follows was removed, it stays where that element was, moving along with later edits.
+    ///
+    /// # Panics
+    /// Panics if `anchor` was removed.
+    pub fn anchor_offset(&self, anchor: Anchor) -> usize {
+        self.anchors.get(anchor).expect("anchor was removed").0
+    }
+
+    /// Whether the element `anchor` follows was removed.
+    /// An anchor at the start or the end with nothing on the side it sticks to is never deleted.
+    ///
+    /// Undoing the removal brings the element back, and with it the anchor.
+    ///
+    /// # Panics
+    /// Panics if `anchor` was removed.
+    pub fn anchor_deleted(&self, anchor: Anchor) -> bool {
+        self.anchors.get(anchor).expect("anchor was removed").1
+    }
+
+    /// Remove `anchor`, so it no longer takes time on every edit.
+    /// Returns `false` if it was already removed.
+    pub fn remove_anchor(&mut self, anchor: Anchor) -> bool {
+        self.anchors.remove(anchor)
+    }
+
+    /// Start recording the edits made to the table from now on for a new subscriber, which reads them with `poll_edits`.
+    ///
+    /// Every edit is reported, including those made by undo, redo and rollback, and by replacing the source or clearing the table.
+    /// Edits are only recorded while someone is subscribed, and are kept until every subscriber has read them.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::{Edit, PieceTable};
+    /// let src = b"hello".to_vec();
+    /// let mut table = PieceTable::new().src(&src).history();
+    /// let highlighter = table.subscribe();
+    /// table.insert_slice(5, b" world");
+    /// table.remove(0);
+    /// assert_eq!(&[Edit { start: 5, removed_len: 0, inserted_len: 6 },
+    ///              Edit { start: 0, removed_len: 1, inserted_len: 0 }], table.poll_edits(highlighter));
+    /// table.undo();
+    /// assert_eq!(&[Edit { start: 0, removed_len: 0, inserted_len: 1 }], table.poll_edits(highlighter));
+    /// assert!(table.poll_edits(highlighter).is_empty());
+    /// ```
+    pub fn subscribe(&mut self) -> Subscription {
+        self.edits.subscribe()
+    }
+
+    /// Stop recording edits for `subscription`, returning `false` if it was already cancelled.
+    pub fn unsubscribe(&mut self, subscription: Subscription) -> bool {
+        self.edits.unsubscribe(subscription)
+    }
+
+    /// Return the edits made since `subscription` was created or last polled, in the order they were made.
+    ///
+    /// The offsets of every edit are relative to the table as it was after the edits before it.
+    ///
+    /// # Panics
+    /// Panics if `subscription` was cancelled.
+    pub fn poll_edits(&mut self, subscription: Subscription) -> &[Edit] {
+        self.edits.poll(subscription)
+    }
+
+    /// Return the edits made since `subscription` was created or last polled along with their rows and columns, like `poll_edits`.
+    ///
+    /// The points are looked up in the line index when each edit is made, taking `O(log p)` time.
+    ///
+    /// # Panics
+    /// Panics if `subscription` was cancelled, or the line index was not enabled when one of the edits was made.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::{InputEdit, PieceTable, Point};
+    /// let src = b"fn main() {\n}\n".to_vec();
+    /// let mut table = PieceTable::new().src(&src).line_index();
+    /// let parser = table.subscribe();
+    /// table.insert_slice(12, b"    loop {}\n");
+    /// assert_eq!(vec![InputEdit {
+    ///     start_byte: 12,
+    ///     old_end_byte: 12,
+    ///     new_end_byte: 24,
+    ///     start_position: Point { row: 1, column: 0 },
+    ///     old_end_position: Point { row: 1, column: 0 },
+    ///     new_end_position: Point { row: 2, column: 0 },
+    /// }], table.poll_input_edits(parser));
+    /// ```
+    pub fn poll_input_edits(&mut self, subscription: Subscription) -> Vec<InputEdit> {
+        self.edits.poll_input(subscription)
+    }
+
+    /// The elements from `idx` to the end of the slice yielded by `chunks` containing it, or an empty slice if not `idx < len`.
+    ///
+    /// Takes `O(log p)` time, and is suitable as the read callback of an incremental parser, which reads the table a piece at a time without copying it.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src = b"hello world".to_vec();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.insert_slice(5, b",");
+    /// assert_eq!(b"llo", table.chunk_at(2));
+    /// assert_eq!(b",", table.chunk_at(5));
+    /// assert!(table.chunk_at(12).is_empty());
+    /// ```
+    pub fn chunk_at(&self, idx: usize) -> &[T] {
+        Chunks::new(self.original_buffer(), &self.adds, self.pieces.clone(), idx.min(self.length), self.length)
+            .next()
+            .unwrap_or(&[])
+    }
+
+    /// Insert an element at `idx`.
+    ///
+    /// `O(log p)` time, but sequential inserts afterwards skip locating the index.
+    ///
+    /// # Panics
+    /// Panics if not `idx <= len`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..100).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.insert(11, 42); // locates index 11
+    /// table.insert(12, 42); // reuses the piece from before
+    /// table.insert(13, 42); // reuses the piece from before
+    /// table.insert(27, 42); // locates index 27
+    /// table.insert(28, 42); // reuses the piece from before
+    /// ```
+    pub fn insert(&mut self, idx: usize, item: T) {
+        assert!(idx <= self.length);
+
+        let start = self.adds.len();
+        self.adds.push(item);
+        self.insert_added(idx, start, 1);
+    }
+
+    /// Insert all elements of `items` at `idx`, in order.
+    ///
+    /// `O(log p)` time plus the time to copy `items`, and at most three pieces are created regardless of the length of `items`.
+    /// Like with `insert`, inserting right after the inserted elements afterwards skips locating the index.
+    ///
+    /// # Panics
+    /// Panics if not `idx <= len`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..5).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.insert_slice(2, &[7, 8, 9]);
+    /// assert_eq!(vec![&0, &1, &7, &8, &9, &2, &3, &4], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn insert_slice(&mut self, idx: usize, items: &[T]) where T: Clone {
+        assert!(idx <= self.length);
+
+        let start = self.adds.len();
+        self.adds.extend_from_slice(items);
+
+        if !items.is_empty() {
+            self.insert_added(idx, start, items.len());
+        }
+    }
+
+    /// Insert all elements yielded by `iterable` at `idx`, in order.
+    ///
+    /// Behaves like `insert_slice`, but takes ownership of the elements.
+    ///
+    /// # Panics
+    /// Panics if not `idx <= len`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..5).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.insert_iter(5, 10..13);
+    /// table.insert_iter(8, vec![20, 21]);
+    /// assert_eq!(vec![&0, &1, &2, &3, &4, &10, &11, &12, &20, &21], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn insert_iter<I>(&mut self, idx: usize, iterable: I) where I: IntoIterator<Item=T> {
+        assert!(idx <= self.length);
+
+        let start = self.adds.len();
+        self.adds.extend(iterable);
+        let length = self.adds.len() - start;
+
+        if length > 0 {
+            self.insert_added(idx, start, length);
+        }
+    }
+
+    /// Insert the `length` elements at `start` of `adds` at `idx`, where they have just been appended.
+    fn insert_added(&mut self, idx: usize, start: usize, length: usize) {
+        self.record();
+        self.index_adds(start);
+
+        let reused = match self.reusable_insert {
+            Some((piece_idx, inserted))
+                if (idx == self.last_idx+1 && inserted)
+                || (idx == self.last_idx && !inserted) => {
+                    // The piece can only grow if nothing was appended to `adds` between it and the new elements.
+                    self.update_piece(piece_idx, |piece| {
+                        let appendable = piece.buffer == Add && piece.start + piece.length == start;
+                        if appendable {
+                            piece.length += length;
+                        }
+                        appendable
+                    })
+                },
+            _ => false,
+        };
+
+        if reused {
+            self.reusable_insert = self.reusable_insert.map(|(piece_idx, _)| (piece_idx, true));
+        } else {
+            let piece = self.piece(Add, start, length);
+            self.raw_insert(idx, piece);
+        }
+
+        self.reusable_remove = None;
+        self.reusable_remove_forward = None;
+        self.anchors.inserted(idx, length);
+        self.push_insertion(Edit {
+            start: idx,
+            removed_len: 0,
+            inserted_len: length,
+        });
+        self.last_idx = idx + length - 1;
+        self.length += length;
+    }
+
+    fn raw_insert(&mut self, idx: usize, new: Piece) {
+        let piece_idx = self.split_piece(idx);
+        self.pieces.insert(piece_idx, new);
+        self.reusable_insert = Some((piece_idx, true));
+
+        self.coalesce_at(piece_idx+1);
+        self.coalesce_at(piece_idx);
+    }
+
+    /// Merge the piece at `piece_idx` into the one before it if it continues it in the same buffer, returning whether it did.
+    ///
+    /// The remembered locations of pieces are forgotten or moved along, so it must be called after setting them.
+    fn coalesce_at(&mut self, piece_idx: usize) -> bool {
+        if piece_idx == 0 || piece_idx >= self.pieces.count() {
+            return false;
+        }
+
+        let prev = *self.pieces.get(piece_idx-1);
+        let next = *self.pieces.get(piece_idx);
+        if prev.buffer != next.buffer || prev.start + prev.length != next.start {
+            return false;
+        }
+
+        self.pieces.remove(piece_idx);
+        self.update_piece(piece_idx-1, |piece| piece.length += next.length);
+
+        // The merged piece still ends where the later one did, but no longer where the earlier one did.
+        self.reusable_insert = match self.reusable_insert {
+            Some((i, inserted)) if i >= piece_idx => Some((i-1, inserted)),
+            Some((i, _)) if i == piece_idx-1 => None,
+            reusable => reusable,
+        };
+        self.reusable_remove = None;
+        self.reusable_remove_forward = None;
+
+        true
+    }
+
+    /// Make sure a piece starts at `idx`, splitting the piece containing it if necessary, and return the index of that piece.
+    fn split_piece(&mut self, idx: usize) -> usize {
+        match self.idx_to_location(idx) {
+            PieceHead(piece_idx) => piece_idx,
+            PieceMid(piece_idx, norm_idx) | PieceTail(piece_idx, norm_idx) => {
+                let orig = *self.pieces.get(piece_idx);
+                self.update_piece(piece_idx, |piece| piece.length = norm_idx);
+
+                let piece = self.piece(orig.buffer, orig.start + norm_idx, orig.length - norm_idx);
+                self.pieces.insert(piece_idx+1, piece);
+
+                piece_idx+1
+            },
+            EOF => self.pieces.count(),
+        }
+    }
+
+    /// Remove the element at the given index.
+    ///
+    /// `O(log p)` operation, but removing sequentially backwards, or repeatedly at the same index, afterwards skips locating the index.
+    ///
+    /// # Panics
+    /// Panics if not `idx < len`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..10).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.remove(5); // locates index 5
+    /// table.remove(4); // reuses the location from before
+    /// table.remove(3); // reuses the location from before
+    /// table.remove(6); // locates index 6
+    /// assert_eq!(vec![&0, &1, &2, &6, &7, &8], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    ///
+    /// Forward deletes, removing at the same index again, reuse the location as well:
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..10).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.remove(4); // locates index 4
+    /// table.remove(4); // reuses the location from before
+    /// table.remove(4); // reuses the location from before
+    /// assert_eq!(vec![&0, &1, &2, &3, &7, &8, &9], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn remove(&mut self, idx: usize) {
+        assert!(idx < self.length);
+        self.record();
+        let before = self.before_edit();
+        let adds_shared = self.adds_shared();
+        let remove: Option<usize>;
 
         match self.reusable_insert {
-            Some((piece_idx, ref mut inserted))
-                if (idx+1 == self.last_idx && !*inserted)
-                || (idx == self.last_idx && *inserted) => {
-                    let piece = &mut self.pieces[piece_idx];
-                    piece.length -= 1;
-                    self.adds.pop();
+            Some((piece_idx, inserted))
+                if (idx+1 == self.last_idx && !inserted)
+                || (idx == self.last_idx && inserted) => {
+                    let length = self.update_piece(piece_idx, |piece| {
+                        piece.length -= 1;
+                        piece.length
+                    });
+
+                    // Earlier revisions may still refer to the removed element.
+                    if !adds_shared && self.adds.pop() {
+                        if let Some(ref mut lines) = self.lines {
+                            lines.truncate_adds(self.adds.len());
+                        }
+                    }
 
                     self.reusable_remove = None;
-                    remove = if piece.length == 0 { Some(piece_idx) } else { None };
+                    self.reusable_remove_forward = self.head_location(piece_idx+1);
+                    remove = if length == 0 { Some(piece_idx) } else { None };
 
-                    *inserted = false;
+                    self.reusable_insert = Some((piece_idx, false));
                 },
             _ => {
-                let location = match self.reusable_remove {
-                    Some(loc) if idx+1 == self.last_idx => loc,
+                let location = match (self.reusable_remove, self.reusable_remove_forward) {
+                    (Some(loc), _) if idx+1 == self.last_idx => loc,
+                    (_, Some(loc)) if idx == self.last_idx => loc,
                     _ => self.idx_to_location(idx),
                 };
 
-                self.raw_remove(location);
+                self.raw_remove(location);
+
+                self.reusable_insert = None;
+                remove = None;
+            }
+        }
+
+        if let Some(piece_idx) = remove {
+            self.pieces.remove(piece_idx);
+            self.reusable_insert = None;
+            self.reusable_remove_forward = self.head_location(piece_idx);
+
+            if piece_idx > 0 {
+                self.reusable_remove = Some(self.tail_location(piece_idx-1));
+            }
+
+            self.coalesce_at(piece_idx);
+        }
+
+        self.anchors.removed(idx, idx+1);
+        self.push_edit(Edit {
+            start: idx,
+            removed_len: 1,
+            inserted_len: 0,
+        }, before);
+        self.last_idx = idx;
+        self.length -= 1;
+    }
+
+    fn raw_remove(&mut self, location: Location) {
+        self.reusable_remove = None;
+
+        match location {
+            PieceHead(piece_idx) => {
+                let remove = self.update_piece(piece_idx, |piece| {
+                    piece.start += 1;
+                    piece.length -= 1;
+                    piece.length == 0
+                });
+
+                if remove {
+                    self.pieces.remove(piece_idx);
+                }
+
+                self.reusable_remove_forward = self.head_location(piece_idx);
+
+                if piece_idx > 0 {
+                    self.reusable_remove = Some(self.tail_location(piece_idx-1));
+                }
+
+                self.coalesce_at(piece_idx);
+            },
+            PieceTail(piece_idx, norm_idx) => {
+                self.update_piece(piece_idx, |piece| piece.length -= 1);
+                self.reusable_remove_forward = self.head_location(piece_idx+1);
+
+                if piece_idx > 0 {
+                    let loc = if norm_idx-1 == 0 {
+                        PieceHead(piece_idx)
+                    } else {
+                        PieceTail(piece_idx, norm_idx-1)
+                    };
+                    self.reusable_remove = Some(loc);
+                }
+
+                self.coalesce_at(piece_idx+1);
+            },
+            PieceMid(piece_idx, norm_idx) => {
+                let orig = *self.pieces.get(piece_idx);
+                self.update_piece(piece_idx, |piece| piece.length = norm_idx);
+
+                let start = norm_idx + 1;
+                if orig.length - start > 0 {
+                    let piece = self.piece(orig.buffer, orig.start + start, orig.length - start);
+                    self.pieces.insert(piece_idx+1, piece);
+                }
+
+                self.reusable_remove_forward = self.head_location(piece_idx+1);
+
+                if piece_idx > 0 {
+                    let loc = if norm_idx-1 == 0 {
+                        PieceHead(piece_idx)
+                    } else {
+                        PieceMid(piece_idx, norm_idx-1)
+                    };
+
+                    self.reusable_remove = Some(loc);
+                }
+            },
+            EOF => self.reusable_remove_forward = None,
+        }
+    }
+
+    /// Remove all elements in `range`.
+    ///
+    /// `O(log p)` time regardless of the number of elements removed, as at most two pieces are trimmed and the pieces in between are cut out of the tree at once.
+    /// Removing sequentially backwards from the start of `range`, or repeatedly at the start of `range`, afterwards skips locating the index.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..10).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.remove_range(2..8);
+    /// assert_eq!(vec![&0, &1, &8, &9], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn remove_range<R>(&mut self, range: R) where R: RangeBounds<usize> {
+        self.detach(range);
+    }
+
+    /// Remove all elements in `range`, returning an iterator over the removed elements.
+    ///
+    /// The elements are removed immediately, like with `remove_range`, whether the iterator is consumed or not.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..10).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// assert_eq!(vec![&3, &4, &5], table.drain(3..6).collect::<Vec<&i32>>());
+    /// assert_eq!(7, table.len());
+    /// ```
+    pub fn drain<'b, R>(&'b mut self, range: R) -> Drain<'b, T> where R: RangeBounds<usize> {
+        let removed = self.detach(range);
+        let length = removed.len();
+
+        Drain {
+            iter: Iter::new(Chunks::new(self.original_buffer(), &self.adds, removed, 0, length)),
+        }
+    }
+
+    /// Replace the elements in `range` with all elements yielded by `replace_with`, returning an iterator over the removed elements.
+    ///
+    /// The table is updated at once, taking `O(log p)` time plus the time to copy the new elements, and it is recorded as a single revision.
+    /// Inserting right after the new elements afterwards skips locating the index.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<i32> = (0..6).collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// assert_eq!(vec![&1, &2], table.splice(1..3, vec![7, 8, 9]).collect::<Vec<&i32>>());
+    /// table.insert(4, 10);
+    /// assert_eq!(vec![&0, &7, &8, &9, &10, &3, &4, &5], table.iter().collect::<Vec<&i32>>());
+    /// ```
+    pub fn splice<'b, R, I>(&'b mut self, range: R, replace_with: I) -> Drain<'b, T>
+        where R: RangeBounds<usize>, I: IntoIterator<Item=T>
+    {
+        let (from, to) = self.bounds(range);
+        assert!(from <= to && to <= self.length);
+
+        let start = self.adds.len();
+        self.adds.extend(replace_with);
+        let length = self.adds.len() - start;
+
+        let removed = self.replace(from, to, start, length);
+
+        Drain {
+            iter: Iter::new(Chunks::new(self.original_buffer(), &self.adds, removed, 0, to - from)),
+        }
+    }
+
+    /// Cut the pieces covering `range` out of the table and return them.
+    fn detach<R>(&mut self, range: R) -> Tree where R: RangeBounds<usize> {
+        let (from, to) = self.bounds(range);
+        assert!(from <= to && to <= self.length);
+
+        let end = self.adds.len();
+        self.replace(from, to, end, 0)
+    }
+
+    /// Replace the elements from `from` to `to` with the `length` elements at `start` of `adds`, where they have just been appended.
+    /// Returns the pieces that were cut out.
+    fn replace(&mut self, from: usize, to: usize, start: usize, length: usize) -> Tree {
+        if from == to && length == 0 {
+            return Tree::new();
+        }
+
+        self.record();
+        self.index_adds(start);
+        let before = self.before_edit();
+
+        let first = self.split_piece(from);
+        let last = self.split_piece(to);
+        let tail = self.pieces.split_off(last);
+        let removed = self.pieces.split_off(first);
+        self.pieces.append(tail);
+
+        if length > 0 {
+            let piece = self.piece(Add, start, length);
+            self.pieces.insert(first, piece);
+
+            self.reusable_insert = Some((first, true));
+            self.reusable_remove = None;
+            self.reusable_remove_forward = None;
+            self.last_idx = from + length - 1;
+        } else {
+            self.reusable_insert = None;
+            self.reusable_remove = if first > 0 {
+                Some(self.tail_location(first-1))
+            } else {
+                None
+            };
+            self.reusable_remove_forward = self.head_location(first);
+            self.last_idx = from;
+        }
+
+        if length > 0 {
+            self.coalesce_at(first+1);
+        }
+        self.coalesce_at(first);
+
+        self.anchors.removed(from, to);
+        self.anchors.inserted(from, length);
+        self.push_edit(Edit {
+            start: from,
+            removed_len: to - from,
+            inserted_len: length,
+        }, before);
+        self.length = self.length - (to - from) + length;
+
+        removed
+    }
+
+    /// Appends an element to the back, efficiently and in constant time.
+    pub fn push(&mut self, value: T) {
+        self.record();
+        let reuse = self.pieces.last().map_or
+            (false, |last| last.buffer == Add
+             && last.start+last.length == self.adds.len());
+
+        self.adds.push(value);
+        let start = self.adds.len()-1;
+        self.index_adds(start);
+
+        if reuse {
+            let last = self.pieces.count()-1;
+            self.update_piece(last, |piece| piece.length += 1);
+        } else {
+            let piece = self.piece(Add, start, 1);
+            self.pieces.push(piece);
+        }
+
+        self.anchors.inserted(self.length, 1);
+        self.push_insertion(Edit {
+            start: self.length,
+            removed_len: 0,
+            inserted_len: 1,
+        });
+        self.last_idx = self.length;
+        self.length += 1;
+        self.reusable_insert = Some((self.pieces.count()-1, true));
+        self.reusable_remove = None;
+        self.reusable_remove_forward = None;
+    }
+
+    /// The location of the last element of the piece at `piece_idx`.
+    fn tail_location(&self, piece_idx: usize) -> Location {
+        match self.pieces.get(piece_idx).length {
+            1 => PieceHead(piece_idx),
+            len => PieceTail(piece_idx, len-1),
+        }
+    }
+
+    /// The location of the first element of the piece at `piece_idx`, if there is such a piece.
+    fn head_location(&self, piece_idx: usize) -> Option<Location> {
+        if piece_idx < self.pieces.count() {
+            Some(PieceHead(piece_idx))
+        } else {
+            None
+        }
+    }
+
+    fn idx_to_location(&self, idx: usize) -> Location {
+        match self.pieces.locate(idx) {
+            Some((i, 0)) => PieceHead(i),
+            Some((i, delta)) if delta == self.pieces.get(i).length-1 => PieceTail(i, delta),
+            Some((i, delta)) => PieceMid(i, delta),
+            None => EOF,
+        }
+    }
+
+    /// The pieces before an edit, if the points of edits are recorded, which share all nodes with the table until the edit changes them.
+    fn before_edit(&self) -> Option<Tree> {
+        if self.lines.is_some() && self.edits.is_active() {
+            Some(self.pieces.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Report `edit` to the subscribers, with its points if `before` holds the pieces it was made to.
+    fn push_edit(&mut self, edit: Edit, before: Option<Tree>) {
+        let old_end = before.map(|pieces| self.point_in(&pieces, edit.start + edit.removed_len));
+        self.push_edit_ending(edit, old_end);
+    }
+
+    /// Report `edit`, which removed nothing, to the subscribers.
+    /// The elements before it are unchanged, so its old end is found at its start after it was made.
+    fn push_insertion(&mut self, edit: Edit) {
+        let before = self.before_edit();
+        self.push_edit(edit, before);
+    }
+
+    /// Report `edit` to the subscribers, with its points if `old_end`, where the removed elements ended, is given.
+    fn push_edit_ending(&mut self, edit: Edit, old_end: Option<Point>) {
+        if !self.edits.is_active() {
+            return;
+        }
+
+        let points = old_end.map(|old_end| {
+            [self.point_in(&self.pieces, edit.start), old_end, self.point_in(&self.pieces, edit.start + edit.inserted_len)]
+        });
+        self.edits.push(edit, points);
+    }
+
+    /// Where the element at `idx` is stored.
+    fn element(&self, idx: usize) -> Option<(Buffer, usize)> {
+        self.pieces.locate(idx).map(|(piece_idx, delta)| {
+            let piece = self.pieces.get(piece_idx);
+            (piece.buffer, piece.start + delta)
+        })
+    }
 
-                self.reusable_insert = None;
-                remove = None;
-            }
+    /// The piece of `length` elements at `start` of `buffer`, with its line breaks counted if the line index is enabled.
+    fn piece(&self, buffer: Buffer, start: usize, length: usize) -> Piece {
+        Piece {
+            start: start,
+            length: length,
+            buffer: buffer,
+            breaks: self.lines.as_ref().map_or(0, |lines| lines.count(buffer, start, start + length)),
         }
+    }
 
-        if let Some(piece_idx) = remove {
-            self.pieces.remove(piece_idx);
-            self.reusable_insert = None;
+    /// Modify the piece at `piece_idx`, counting its line breaks again afterwards.
+    fn update_piece<F, R>(&mut self, piece_idx: usize, f: F) -> R where F: FnOnce(&mut Piece) -> R {
+        let lines = &self.lines;
 
-            if piece_idx > 0 {
-                let idx = piece_idx-1;
-                let len = self.pieces[idx].length;
-                let loc = if len == 1 {
-                    PieceHead(idx)
-                } else {
-                    PieceTail(idx, len-1)
-                };
+        self.pieces.update(piece_idx, |piece| {
+            let result = f(piece);
+            if let Some(ref lines) = *lines {
+                piece.breaks = lines.count(piece.buffer, piece.start, piece.start + piece.length);
+            }
+            result
+        })
+    }
 
-                self.reusable_remove = Some(loc);
+    /// Index the line breaks among the elements from `start` of `adds`, which have just been appended.
+    fn index_adds(&mut self, start: usize) {
+        if let Some(ref mut lines) = self.lines {
+            let mut pos = start;
+            while pos < self.adds.len() {
+                let elements = self.adds.slice_from(pos, self.adds.len());
+                lines.index_adds(elements, pos);
+                pos += elements.len();
             }
         }
+    }
 
-        self.last_idx = idx;
-        self.length -= 1;
+    fn line_index_ref(&self) -> &LineIndex<T> {
+        self.lines.as_ref().expect("line index not enabled, see `PieceTable::line_index`")
     }
 
-    fn raw_remove(&mut self, location: Location) {
-        self.reusable_remove = None;
+    /// The element at `offset` in `piece`.
+    fn piece_element(&self, piece: &Piece, offset: usize) -> &T {
+        match piece.buffer {
+            Add => self.adds.get(piece.start + offset),
+            Original => &self.original_buffer()[piece.start + offset],
+        }
+    }
 
-        match location {
-            PieceHead(piece_idx) => {
-                let remove = {
-                    let piece = &mut self.pieces[piece_idx];
-                    piece.start += 1;
-                    piece.length -= 1;
-                    piece.length == 0
-                };
+    fn original_buffer(&self) -> &[T] {
+        self.original.as_ref().map_or(&[], |original| original.as_ref())
+    }
+}
 
-                if remove {
-                    self.pieces.remove(piece_idx);
-                }
+#[cfg(feature = "mmap")]
+impl<'a> PieceTable<'a, u8, MmapSource> {
 
-                if piece_idx > 0 {
-                    let idx = piece_idx-1;
-                    let len = self.pieces[idx].length;
-                    let loc = if len == 1 {
-                        PieceHead(idx)
-                    } else {
-                        PieceTail(idx, len-1)
-                    };
+    /// Construct a new `PieceTable` with the file at `path` memory-mapped as its source.
+    ///
+    /// Only the parts of the file that are read are loaded into memory, so huge files can be edited without holding them in memory.
+    /// A private copy of the file is mapped, so the table is unaffected by changing or truncating the file afterwards, see `MmapSource::open`.
+    /// Requires the `mmap` feature.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use piecetable::PieceTable;
+    /// let mut table = PieceTable::open("huge.log").unwrap();
+    /// table.insert(0, b'#');
+    /// ```
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<PieceTable<'a, u8, MmapSource>> {
+        MmapSource::open(path).map(|src| PieceTable::new().source(src))
+    }
+}
 
-                    self.reusable_remove = Some(loc);
-                }
-            },
-            PieceTail(piece_idx, norm_idx) => {
-                self.pieces[piece_idx].length -= 1;
+/// The number of slices handed to the writer at once by `write_to`.
+const WRITE_SLICES: usize = 64;
 
-                if piece_idx > 0 {
-                    let loc = if norm_idx-1 == 0 {
-                        PieceHead(piece_idx)
-                    } else {
-                        PieceTail(piece_idx, norm_idx-1)
-                    };
-                    self.reusable_remove = Some(loc);
-                }
-            },
-            PieceMid(piece_idx, norm_idx) => {
-                let orig = self.pieces[piece_idx];
-                self.pieces[piece_idx].length = norm_idx;
+impl<'a, S: 'a + AsRef<[u8]>> PieceTable<'a, u8, S> {
 
-                let start = norm_idx + 1;
-                if orig.length - start > 0 {
-                    self.pieces.insert(piece_idx+1, Piece {
-                        start: orig.start + start,
-                        length: orig.length - start,
-                        buffer: orig.buffer,
-                    });
+    /// Write all elements to `writer`, handing it the contiguous part of every piece without copying.
+    ///
+    /// Pieces are written in batches with `write_vectored`, so saving takes `O(p)` time and calls to the writer, and allocates nothing.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src = b"hello world".to_vec();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.insert(5, b',');
+    ///
+    /// let mut saved = Vec::new();
+    /// table.write_to(&mut saved).unwrap();
+    /// assert_eq!(b"hello, world".to_vec(), saved);
+    /// ```
+    pub fn write_to<W: Write>(&'a self, mut writer: W) -> io::Result<()> {
+        let mut chunks = self.chunks();
+        let mut slices = [IoSlice::new(&[]); WRITE_SLICES];
+
+        loop {
+            let mut n = 0;
+            while n < WRITE_SLICES {
+                match chunks.next() {
+                    Some(chunk) => {
+                        slices[n] = IoSlice::new(chunk);
+                        n += 1;
+                    },
+                    None => break,
                 }
+            }
 
-                if piece_idx > 0 {
-                    let loc = if norm_idx-1 == 0 {
-                        PieceHead(piece_idx)
-                    } else {
-                        PieceMid(piece_idx, norm_idx-1)
-                    };
+            if n == 0 {
+                return writer.flush();
+            }
 
-                    self.reusable_remove = Some(loc);
+            write_all_vectored(&mut writer, &mut slices[..n])?;
+        }
+    }
+}
+
+impl<'a, S: 'a + AsRef<[char]>> PieceTable<'a, char, S> {
+
+    /// Write all elements to `writer` encoded as UTF-8.
+    ///
+    /// Characters are encoded into a fixed buffer on the stack which is written whenever it fills up, so nothing is allocated.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::PieceTable;
+    /// let src: Vec<char> = "grüß".chars().collect();
+    /// let mut table = PieceTable::new().src(&src);
+    /// table.push('e');
+    ///
+    /// let mut saved = Vec::new();
+    /// table.write_to(&mut saved).unwrap();
+    /// assert_eq!("grüße".as_bytes(), &saved[..]);
+    /// ```
+    pub fn write_to<W: Write>(&'a self, mut writer: W) -> io::Result<()> {
+        let mut buf = [0; 4096];
+        let mut len = 0;
+
+        for chunk in self.chunks() {
+            for c in chunk {
+                if len + c.len_utf8() > buf.len() {
+                    writer.write_all(&buf[..len])?;
+                    len = 0;
                 }
-            },
-            EOF => {},
+
+                len += c.encode_utf8(&mut buf[len..]).len();
+            }
         }
+
+        writer.write_all(&buf[..len])?;
+        writer.flush()
     }
 
-    /// Appends an element to the back, efficiently and in constant time.
-    pub fn push(&mut self, value: T) {
-        let reuse = self.pieces.last().map_or
-            (false, |last| last.buffer == Add
-             && last.start+last.length == self.adds.len());
+    /// The Language Server Protocol position of the element at `idx`.
+    ///
+    /// Takes `O(log p)` time plus the time to encode the characters before `idx` in its line.
+    ///
+    /// # Panics
+    /// Panics if the line index is not enabled or not `idx <= len`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::{PieceTable, Position, PositionEncoding};
+    /// let src: Vec<char> = "fn main() {\n    \"🦀\".len()\n}".chars().collect();
+    /// let table = PieceTable::new().src(&src).line_index();
+    /// assert_eq!(Position::new(1, 8), table.offset_to_position(19, PositionEncoding::Utf16));
+    /// assert_eq!(Position::new(1, 10), table.offset_to_position(19, PositionEncoding::Utf8));
+    /// ```
+    pub fn offset_to_position(&self, idx: usize, encoding: PositionEncoding) -> Position {
+        let (line, col) = self.offset_to_line_col(idx);
+        let character = self.chunks_in(idx - col .. idx)
+            .flat_map(|chunk| chunk.iter())
+            .map(|&c| encoding.units(c))
+            .sum();
+
+        Position::new(line, character)
+    }
 
-        self.adds.push(value);
+    /// The index of the element at a Language Server Protocol position.
+    ///
+    /// As in the protocol, a column past the end of its line means the end of the line, before a `'\r'` ending it along with the `'\n'`,
+    /// and a line past the last line means the end.
+    /// A column inside a character, such as between the halves of a UTF-16 surrogate pair, is rounded down to the start of that character.
+    ///
+    /// Takes `O(log p)` time plus the time to encode the characters before the position in its line.
+    ///
+    /// # Panics
+    /// Panics if the line index is not enabled.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::{PieceTable, Position, PositionEncoding};
+    /// let src: Vec<char> = "fn main() {\n    \"🦀\".len()\n}".chars().collect();
+    /// let table = PieceTable::new().src(&src).line_index();
+    /// assert_eq!(19, table.position_to_offset(Position::new(1, 8), PositionEncoding::Utf16));
+    /// assert_eq!(17, table.position_to_offset(Position::new(1, 6), PositionEncoding::Utf16));
+    /// assert_eq!(25, table.position_to_offset(Position::new(1, 80), PositionEncoding::Utf16));
+    /// ```
+    pub fn position_to_offset(&self, position: Position, encoding: PositionEncoding) -> usize {
+        if position.line >= self.line_count() {
+            return self.length;
+        }
 
-        if reuse {
-            self.pieces.last_mut().unwrap().length += 1;
-        } else {
-            self.pieces.push(Piece {
-                start: self.adds.len()-1,
-                length: 1,
-                buffer: Add,
-            });
+        let mut idx = self.line_to_offset(position.line);
+        let mut units = 0;
+        let mut carriage_return = false;
+
+        for chunk in self.chunks_in(idx..) {
+            for &c in chunk {
+                if c == '\n' {
+                    return if carriage_return { idx - 1 } else { idx };
+                }
+                units += encoding.units(c);
+                if units > position.character {
+                    return idx;
+                }
+                carriage_return = c == '\r';
+                idx += 1;
+            }
         }
 
-        self.last_idx = self.length;
-        self.length += 1;
-        self.reusable_insert = Some((self.pieces.len()-1, true));
-        self.reusable_remove = None;
+        idx
     }
 
-    fn idx_to_location(&self, idx: usize) -> Location {
-        let mut offset = 0;
-        for (i, piece) in self.pieces.iter().enumerate() {
-            if idx >= offset && idx < offset + piece.length {
-                return match idx - offset {
-                    0 => PieceHead(i),
-                    delta if delta == piece.length-1 => PieceTail(i, delta),
-                    delta => PieceMid(i, delta),
-                };
+    /// Apply a batch of Language Server Protocol edits as a single revision.
+    ///
+    /// As in the protocol, every range refers to the text before any of the edits, and edits inserting at the same position are inserted in the order given.
+    /// Returns `false` and leaves the table unchanged if a range ends before it starts or two ranges overlap.
+    ///
+    /// Every position is converted before the first edit, taking `O(e log e)` time for sorting `e` edits on top of the conversions and the edits themselves.
+    ///
+    /// # Panics
+    /// Panics if the line index is not enabled.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::{PieceTable, Position, PositionEncoding, TextEdit};
+    /// let src: Vec<char> = "let x = 1;\nlet y = x;".chars().collect();
+    /// let mut table = PieceTable::new().src(&src).line_index();
+    /// let edits = vec![
+    ///     TextEdit { range: Position::new(1, 8) .. Position::new(1, 9), new_text: "value".to_string() },
+    ///     TextEdit { range: Position::new(0, 4) .. Position::new(0, 5), new_text: "value".to_string() },
+    /// ];
+    /// assert!(table.apply_edits(&edits, PositionEncoding::Utf16));
+    /// assert_eq!("let value = 1;\nlet y = value;", table.iter().collect::<String>());
+    /// ```
+    pub fn apply_edits(&mut self, edits: &[TextEdit], encoding: PositionEncoding) -> bool {
+        let mut ranges = edits.iter().enumerate().map(|(i, edit)| {
+            let from = self.position_to_offset(edit.range.start, encoding);
+            let to = self.position_to_offset(edit.range.end, encoding);
+            (from, to, i)
+        }).collect::<Vec<(usize, usize, usize)>>();
+
+        // Sorting by the end as well puts inserts before a replacement starting at the same position, which they do not overlap.
+        ranges.sort();
+
+        let valid = ranges.iter().all(|&(from, to, _)| from <= to)
+            && ranges.windows(2).all(|pair| pair[0].1 <= pair[1].0);
+        if !valid {
+            return false;
+        }
+
+        // Applying the edits from the back keeps the offsets of those not applied yet valid.
+        self.transact(|table| {
+            for &(from, to, i) in ranges.iter().rev() {
+                table.splice(from .. to, edits[i].new_text.chars());
             }
+        });
+
+        true
+    }
+}
+
+/// The start and end of `range`, where `length` is the end if it is unbounded.
+fn bounds<R>(range: R, length: usize) -> (usize, usize) where R: RangeBounds<usize> {
+    let from = match range.start_bound() {
+        Bound::Included(&x) => x,
+        Bound::Excluded(&x) => x+1,
+        Bound::Unbounded => 0,
+    };
+
+    let to = match range.end_bound() {
+        Bound::Included(&x) => x+1,
+        Bound::Excluded(&x) => x,
+        Bound::Unbounded => length,
+    };
+
+    (from, to)
+}
 
-            offset += piece.length;
+/// Write all of `slices`, retrying after partial writes.
+fn write_all_vectored<W: Write>(writer: &mut W, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
         }
+    }
+
+    Ok(())
+}
+
+/// Rolls back the transaction it was created for, unless disarmed by setting `depth` to zero.
+struct TransactionGuard<'t, 'a: 't, T: 'a, S: 'a + AsRef<[T]>> {
+    table: &'t mut PieceTable<'a, T, S>,
+    depth: usize,
+}
 
-        EOF
+impl<'t, 'a, T, S: AsRef<[T]>> Drop for TransactionGuard<'t, 'a, T, S> {
+    fn drop(&mut self) {
+        while self.depth > 0 && self.table.transactions.len() >= self.depth {
+            self.table.rollback();
+        }
     }
+}
 
-    fn get_buffer(&'a self, piece: &Piece) -> &'a [T] {
-        match piece.buffer {
-            Add => &self.adds,
-            Original => self.original,
+impl<'a, T: 'a> Iter<'a, T> {
+    fn new(chunks: Chunks<'a, T>) -> Iter<'a, T> {
+        Iter {
+            chunks: chunks,
+            // Need iterators that just close.
+            front: [].iter(),
+            back: [].iter(),
         }
     }
 }
@@ -506,49 +2030,216 @@ impl<'a, T> Iterator for Iter<'a, T> {
     type Item = &'a T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next) = self.it.next() {
-            Some(next)
+        loop {
+            if let Some(next) = self.front.next() {
+                return Some(next);
+            }
+
+            match self.chunks.next() {
+                Some(chunk) => self.front = chunk.iter(),
+                None => return self.back.next(),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.front.len() + self.chunks.remaining() + self.back.len();
+        (len, Some(len))
+    }
+
+    fn nth(&mut self, mut n: usize) -> Option<Self::Item> {
+        if n < self.front.len() {
+            return self.front.nth(n);
+        }
+        n -= self.front.len();
+
+        if n < self.chunks.remaining() {
+            self.chunks.from += n;
+            self.front = self.chunks.next().unwrap().iter();
+            self.front.next()
         } else {
-            self.piece_idx += 1;
+            n -= self.chunks.remaining();
+            self.front = self.back.as_slice()[0 .. 0].iter();
+            self.chunks.from = self.chunks.to;
+            self.back.nth(n)
+        }
+    }
+}
 
-            if self.piece_idx >= self.table.pieces.len() {
-                None
-            } else {
-                let piece = self.table.pieces[self.piece_idx];
-                let buf = self.table.get_buffer(&piece);
+impl<'a, T> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(next) = self.back.next_back() {
+                return Some(next);
+            }
 
-                self.it = buf[(piece.start .. piece.start + piece.length)].iter();
-                self.next()
+            match self.chunks.next_back() {
+                Some(chunk) => self.back = chunk.iter(),
+                None => return self.front.next_back(),
             }
         }
     }
+
+    fn nth_back(&mut self, mut n: usize) -> Option<Self::Item> {
+        if n < self.back.len() {
+            return self.back.nth_back(n);
+        }
+        n -= self.back.len();
+
+        if n < self.chunks.remaining() {
+            self.chunks.to -= n;
+            self.back = self.chunks.next_back().unwrap().iter();
+            self.back.next_back()
+        } else {
+            n -= self.chunks.remaining();
+            self.back = self.front.as_slice()[0 .. 0].iter();
+            self.chunks.to = self.chunks.from;
+            self.front.nth_back(n)
+        }
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Iter<'a, T> {}
+
+impl<'a, T: 'a> Chunks<'a, T> {
+    /// Iterates the elements from `from` to `to` of `pieces`, which refer to `original` and `adds`.
+    fn new(original: &'a [T], adds: &'a Adds<T>, pieces: Tree, from: usize, to: usize) -> Chunks<'a, T> {
+        Chunks {
+            original: original,
+            adds: adds,
+            pieces: pieces,
+            from: from,
+            to: to,
+        }
+    }
+
+    /// The number of elements left in the chunks.
+    fn remaining(&self) -> usize {
+        self.to - self.from
+    }
+
+    /// The elements of `piece` from `start` up to `end`, or up to the end of a chunk of the add buffer.
+    fn slice_from(&self, piece: &Piece, start: usize, end: usize) -> &'a [T] {
+        match piece.buffer {
+            Add => self.adds.slice_from(start, end),
+            Original => &self.original[start .. end],
+        }
+    }
+
+    /// The elements of `piece` from `start` up to `end`, or from the start of a chunk of the add buffer.
+    fn slice_to(&self, piece: &Piece, start: usize, end: usize) -> &'a [T] {
+        match piece.buffer {
+            Add => self.adds.slice_to(start, end),
+            Original => &self.original[start .. end],
+        }
+    }
+}
+
+impl<'a, T> Iterator for Chunks<'a, T> {
+    type Item = &'a [T];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.from == self.to {
+            return None;
+        }
+
+        let (piece_idx, offset) = self.pieces.locate(self.from).unwrap();
+        let piece = *self.pieces.get(piece_idx);
+        let start = piece.start + offset;
+        let length = std::cmp::min(piece.length - offset, self.remaining());
+
+        let chunk = self.slice_from(&piece, start, start + length);
+        self.from += chunk.len();
+
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Every chunk holds at least one element.
+        let remaining = self.remaining();
+        (std::cmp::min(remaining, 1), Some(remaining))
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Chunks<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.from == self.to {
+            return None;
+        }
+
+        let (piece_idx, offset) = self.pieces.locate(self.to - 1).unwrap();
+        let piece = *self.pieces.get(piece_idx);
+        let end = piece.start + offset + 1;
+        let length = std::cmp::min(offset + 1, self.remaining());
+
+        let chunk = self.slice_to(&piece, end - length, end);
+        self.to -= chunk.len();
+
+        Some(chunk)
+    }
 }
 
 impl<'a, T> Iterator for Range<'a, T> {
     type Item = &'a T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.idx >= self.to {
-            None
-        } else {
-            self.idx += 1;
-            self.iter.next()
-        }
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.iter.nth(n)
     }
 }
 
-impl<'a, T> std::iter::FromIterator<T> for PieceTable<'a, T> {
-    fn from_iter<I>(iterable: I) -> PieceTable<'a, T> where I: IntoIterator<Item=T> {
-        use std::iter::FromIterator;
+impl<'a, T> DoubleEndedIterator for Range<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back()
+    }
 
-        let mut table = PieceTable::new();
-        table.adds = FromIterator::from_iter(iterable);
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        self.iter.nth_back(n)
+    }
+}
 
-        table.pieces = vec![Piece {
-            start: 0,
-            length: table.adds.len(),
-            buffer: Add,
-        }];
+impl<'a, T> ExactSizeIterator for Range<'a, T> {}
+
+impl<'a, T> Iterator for Drain<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Drain<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Drain<'a, T> {}
+
+impl<'a, T, S> std::iter::FromIterator<T> for PieceTable<'a, T, S> {
+    fn from_iter<I>(iterable: I) -> PieceTable<'a, T, S> where I: IntoIterator<Item=T> {
+        let mut table = PieceTable::default();
+        table.adds.extend(iterable);
+
+        if table.adds.len() > 0 {
+            table.pieces.push(Piece {
+                start: 0,
+                length: table.adds.len(),
+                buffer: Add,
+                breaks: 0,
+            });
+        }
 
         table.length = table.adds.len();
 
@@ -556,26 +2247,41 @@ impl<'a, T> std::iter::FromIterator<T> for PieceTable<'a, T> {
     }
 }
 
-impl<'a, T> std::iter::Extend<T> for PieceTable<'a, T> {
+impl<'a, T, S: 'a + AsRef<[T]>> std::iter::Extend<T> for PieceTable<'a, T, S> {
     fn extend<I>(&mut self, iterable: I) where I: IntoIterator<Item=T> {
         let start = self.adds.len();
         self.adds.extend(iterable);
         let length = self.adds.len() - start;
 
-        self.pieces.push(Piece {
-            start: start,
-            length: length,
-            buffer: Add,
-        });
+        if length == 0 {
+            return;
+        }
+
+        self.record();
+        self.index_adds(start);
+        let piece = self.piece(Add, start, length);
+        self.pieces.push(piece);
+
+        let last = self.pieces.count()-1;
+        self.coalesce_at(last);
 
+        self.anchors.inserted(self.length, length);
+        self.push_insertion(Edit {
+            start: self.length,
+            removed_len: 0,
+            inserted_len: length,
+        });
         self.length += length;
+        self.reusable_insert = None;
+        self.reusable_remove = None;
+        self.reusable_remove_forward = None;
     }
 }
 
-impl<'a, T> Index<usize> for PieceTable<'a, T> {
+impl<'a, T, S: 'a + AsRef<[T]>> Index<usize> for PieceTable<'a, T, S> {
     type Output = T;
 
-    /// Note: Reading an index takes `O(p)` time, use iterators for fast sequential access.
+    /// Note: Reading an index takes `O(log p)` time, use iterators for fast sequential access.
     fn index<'b>(&'b self, idx: usize) -> &'b T {
         let (piece_idx, norm_idx) = match self.idx_to_location(idx) {
             PieceHead(piece_idx) => (piece_idx, 0),
@@ -584,43 +2290,50 @@ impl<'a, T> Index<usize> for PieceTable<'a, T> {
             EOF => panic!("PieceTable out of bounds: {}", idx),
         };
 
-        let ref piece = self.pieces[piece_idx];
-        match piece.buffer {
-            Add => &self.adds[piece.start + norm_idx],
-            Original => &self.original[piece.start + norm_idx],
-        }
+        self.piece_element(self.pieces.get(piece_idx), norm_idx)
     }
 }
 
-fn push_all_at<T>(v: &mut Vec<T>, offset: usize, s: &[T]) where T: Copy {
-    match (v.len(), s.len()) {
-        (_, 0) => (),
-        (current_len, _) => {
-            v.reserve_exact(s.len());
-            unsafe {
-                v.set_len(current_len + s.len());
-                let to_move = current_len - offset;
-                let src = v.as_mut_ptr().offset(offset as isize);
-                if to_move > 0 {
-                    let dst = src.offset(s.len() as isize);
-                    std::ptr::copy(src, dst, to_move);
-                }
-                std::ptr::copy_nonoverlapping(s.as_ptr(), src, s.len());
-            }
-        },
+impl<'a, T, S: Clone> Clone for PieceTable<'a, T, S> {
+    /// Takes `O(c)` time for the `c` chunks of the add buffer, which is `O(log n)` for `n` inserted elements plus one for every clone that inserted elements before the table did,
+    /// plus the time to clone the source and the bookkeeping of the history, anchors, open transactions and unseen edits.
+    fn clone(&self) -> PieceTable<'a, T, S> {
+        PieceTable {
+            original: self.original.clone(),
+            adds: self.adds.clone(),
+            pieces: self.pieces.clone(),
+            last_idx: self.last_idx,
+            length: self.length,
+            reusable_insert: self.reusable_insert,
+            reusable_remove: self.reusable_remove,
+            reusable_remove_forward: self.reusable_remove_forward,
+            history: self.history.clone(),
+            transactions: self.transactions.clone(),
+            lines: self.lines.clone(),
+            anchors: self.anchors.clone(),
+            edits: self.edits.clone(),
+            marker: PhantomData,
+        }
     }
 }
 
-impl<'a, T> Default for PieceTable<'a, T> {
-    fn default() -> PieceTable<'a, T> {
+impl<'a, T, S> Default for PieceTable<'a, T, S> {
+    fn default() -> PieceTable<'a, T, S> {
         PieceTable {
-            original: &[],
-            adds: Vec::new(),
-            pieces: Vec::new(),
+            original: None,
+            adds: Adds::with_capacity(0),
+            pieces: Tree::new(),
             last_idx: 0,
             length: 0,
             reusable_insert: None,
             reusable_remove: None,
+            reusable_remove_forward: None,
+            history: None,
+            transactions: Vec::new(),
+            lines: None,
+            anchors: Anchors::new(),
+            edits: EditLog::new(),
+            marker: PhantomData,
         }
     }
 }
@@ -0,0 +1,174 @@
+//! Optional index of the line breaks in a `PieceTable`.
+//!
+//! The positions of the line breaks in the source and the add buffer are kept in sorted vectors, which clones of the table share.
+//! As the add buffer is only appended to, so is its vector, which is stored in chunks like the add buffer itself,
+//! and the number of line breaks in any piece can be counted with two binary searches.
+//! Every piece stores that count and every node in the piece tree caches the sum for its subtree, so lines can be located in `O(log p)` time.
+
+use std::iter::Iterator;
+use std::collections::Bound::*;
+use std::sync::Arc;
+
+use {Buffer, PieceTable, Range};
+use adds::Adds;
+
+/// Elements that can end a line.
+pub trait LineBreak {
+    fn is_line_break(&self) -> bool;
+}
+
+impl LineBreak for u8 {
+    fn is_line_break(&self) -> bool {
+        *self == b'\n'
+    }
+}
+
+impl LineBreak for char {
+    fn is_line_break(&self) -> bool {
+        *self == '\n'
+    }
+}
+
+#[derive(Debug, Hash)]
+pub(crate) struct LineIndex<T> {
+    is_break: fn(&T) -> bool,
+    original: Arc<Vec<usize>>,
+    adds: Adds<usize>,
+}
+
+impl<T> LineIndex<T> {
+    pub fn new(is_break: fn(&T) -> bool) -> LineIndex<T> {
+        LineIndex {
+            is_break: is_break,
+            original: Arc::new(Vec::new()),
+            adds: Adds::with_capacity(0),
+        }
+    }
+
+    /// Index the line breaks of a new source.
+    pub fn index_original(&mut self, original: &[T]) {
+        let is_break = self.is_break;
+        self.original = Arc::new(original.iter().enumerate()
+            .filter(|&(_, x)| is_break(x))
+            .map(|(i, _)| i)
+            .collect());
+    }
+
+    /// Index the line breaks among `elements`, which have just been appended at `start` of `adds`.
+    pub fn index_adds(&mut self, elements: &[T], start: usize) {
+        let is_break = self.is_break;
+        self.adds.extend(elements.iter().enumerate()
+                         .filter(|&(_, x)| is_break(x))
+                         .map(|(i, _)| start + i));
+    }
+
+    /// Forget the line breaks of all of `adds`, which is about to be replaced.
+    pub fn clear_adds(&mut self) {
+        self.adds.clear();
+    }
+
+    /// Forget the line breaks from `len` of `adds`, where elements have been removed.
+    pub fn truncate_adds(&mut self, len: usize) {
+        let kept = self.adds.partition_point(|&pos| pos < len);
+        self.adds.truncate(kept);
+    }
+
+    pub fn clear(&mut self) {
+        self.original = Arc::new(Vec::new());
+        self.adds.clear();
+    }
+
+    /// The number of line breaks before `pos` of `buffer`.
+    fn rank(&self, buffer: Buffer, pos: usize) -> usize {
+        match buffer {
+            Buffer::Add => self.adds.partition_point(|&x| x < pos),
+            Buffer::Original => self.original.partition_point(|&x| x < pos),
+        }
+    }
+
+    /// The number of line breaks from `start` up to `end` of `buffer`.
+    pub fn count(&self, buffer: Buffer, start: usize, end: usize) -> usize {
+        self.rank(buffer, end) - self.rank(buffer, start)
+    }
+
+    /// The position of the `n`th line break from `start` of `buffer`, counting from zero.
+    pub fn nth(&self, buffer: Buffer, start: usize, n: usize) -> usize {
+        let i = self.rank(buffer, start) + n;
+        match buffer {
+            Buffer::Add => *self.adds.get(i),
+            Buffer::Original => self.original[i],
+        }
+    }
+}
+
+impl<T> Clone for LineIndex<T> {
+    /// Shares the positions of the line breaks.
+    fn clone(&self) -> LineIndex<T> {
+        LineIndex {
+            is_break: self.is_break,
+            original: self.original.clone(),
+            adds: self.adds.clone(),
+        }
+    }
+}
+
+/// Struct for iterating the lines of a `PieceTable`, created by `PieceTable::lines`.
+///
+/// Every line is a `Range` over its elements, without the line break ending it.
+pub struct Lines<'a, T: 'a, S: 'a = &'a [T]> {
+    table: &'a PieceTable<'a, T, S>,
+    line: usize,
+    end: usize,
+}
+
+impl<'a, T: 'a, S: 'a + AsRef<[T]>> Lines<'a, T, S> {
+    pub(crate) fn new(table: &'a PieceTable<'a, T, S>) -> Lines<'a, T, S> {
+        Lines {
+            table: table,
+            line: 0,
+            end: table.line_count(),
+        }
+    }
+
+    fn get(&self, line: usize) -> Range<'a, T> {
+        let from = self.table.line_to_offset(line);
+        let to = if line+1 < self.table.line_count() {
+            self.table.line_to_offset(line+1) - 1
+        } else {
+            self.table.len()
+        };
+
+        self.table.range(Included(from), Excluded(to))
+    }
+}
+
+impl<'a, T, S: AsRef<[T]>> Iterator for Lines<'a, T, S> {
+    type Item = Range<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.line == self.end {
+            return None;
+        }
+
+        self.line += 1;
+        Some(self.get(self.line-1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.line;
+        (len, Some(len))
+    }
+}
+
+impl<'a, T, S: AsRef<[T]>> DoubleEndedIterator for Lines<'a, T, S> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.line == self.end {
+            return None;
+        }
+
+        self.end -= 1;
+        Some(self.get(self.end))
+    }
+}
+
+impl<'a, T, S: AsRef<[T]>> ExactSizeIterator for Lines<'a, T, S> {}
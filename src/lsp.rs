@@ -0,0 +1,47 @@
+//! Positions and edits as used by the Language Server Protocol.
+//!
+//! A position is a line and a column counted in code units of the negotiated encoding, rather than in characters.
+//! The line index locates the line in `O(log p)` time, so only the characters before the position in its own line are encoded.
+
+use std::ops::Range;
+
+/// The encoding whose code units the column of a `Position` counts.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum PositionEncoding {
+    Utf8,
+    /// The default of the Language Server Protocol.
+    Utf16,
+}
+
+/// A line and a column in code units, both counting from zero.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+/// A replacement of the text in `range` with `new_text`.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct TextEdit {
+    pub range: Range<Position>,
+    pub new_text: String,
+}
+
+impl Position {
+    pub fn new(line: usize, character: usize) -> Position {
+        Position {
+            line: line,
+            character: character,
+        }
+    }
+}
+
+impl PositionEncoding {
+    /// The number of code units encoding `c`.
+    pub fn units(self, c: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8(),
+            PositionEncoding::Utf16 => c.len_utf16(),
+        }
+    }
+}
@@ -0,0 +1,554 @@
+//! Marks, ranges of a `PieceTable` whose ends are moved by its edits, kept in order so an edit only moves the marks after it.
+//!
+//! The marks form a treap ordered by start, where those with `Gravity::Left` come first among the marks starting at the same offset.
+//! An edit splits the treap where it happens and shifts the part after it lazily, recording the shift at the root of that part,
+//! so it takes `O(log m)` time for `m` marks, plus `O(log m)` for every mark it ends inside of, and `O(1)` for every mark starting inside a removed range.
+//! Every node caches the largest end below it, which finds the marks ending after a position without visiting the others.
+//! The marks live in an arena and are addressed by their slot, and the ends of one are found by adding up the shifts of the nodes above it.
+//!
+//! An end that comes before the start, which only setting the ends again can bring about, is held at the start and moves along with it,
+//! while the actual end is kept as a mark of its own, until a removal takes both ends to the same offset.
+
+use std::ops;
+
+use anchors::Gravity;
+
+const NIL: usize = !0;
+
+#[derive(Debug, Clone, Hash)]
+struct Node<V> {
+    // `None` while the slot is free.
+    value: Option<V>,
+    // Correct once the pending shifts of all nodes above have been applied, like `end` and `max_end`.
+    start: usize,
+    start_gravity: Gravity,
+    end: usize,
+    // The gravity the end moves by, which is that of the start while the end is held at the start.
+    end_gravity: Gravity,
+    // The gravity the end was given.
+    own_end_gravity: Gravity,
+    // The slot of the actual end in `held` while the end is held at the start, or `NIL`.
+    held: usize,
+    // The largest key of an end below and including this node.
+    max_end: (usize, bool),
+    // Not yet applied to the nodes below.
+    shift: isize,
+    priority: u64,
+    left: usize,
+    right: usize,
+    parent: usize,
+}
+
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct Marks<V> {
+    nodes: Vec<Node<V>>,
+    free: Vec<usize>,
+    root: usize,
+    // The actual ends of the marks whose end is held at their start, created when the first one is.
+    held: Option<Box<Marks<()>>>,
+    // Advanced for every mark to derive its priority from.
+    seed: u64,
+}
+
+/// The key ordering an offset with `gravity`, where `Gravity::Left` comes first.
+fn key(offset: usize, gravity: Gravity) -> (usize, bool) {
+    (offset, gravity == Gravity::Right)
+}
+
+/// Where the end at `offset` with `gravity` moves to when `length` elements are inserted at `idx`.
+fn inserted(offset: usize, gravity: Gravity, idx: usize, length: usize) -> usize {
+    if key(offset, gravity) >= key(idx, Gravity::Right) {
+        offset + length
+    } else {
+        offset
+    }
+}
+
+/// Where the end at `offset` with `gravity` moves to when the elements from `from` to `to` are removed.
+/// An end is inside if the element on the side its gravity sticks to was removed.
+fn removed(offset: usize, gravity: Gravity, from: usize, to: usize) -> usize {
+    if key(offset, gravity) < key(from, Gravity::Right) {
+        offset
+    } else if key(offset, gravity) < key(to, Gravity::Right) {
+        from
+    } else {
+        offset - (to - from)
+    }
+}
+
+impl<V> Marks<V> {
+    pub fn new() -> Marks<V> {
+        Marks {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: NIL,
+            held: None,
+            seed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root == NIL
+    }
+
+    /// Add a mark from `start` to `end`, both along with their gravity, after those already starting there with the same gravity.
+    /// Returns its slot.
+    pub fn insert(&mut self, start: (usize, Gravity), end: (usize, Gravity), value: V) -> usize {
+        // SplitMix64, so the priorities are spread out no matter how the slots are reused.
+        self.seed = self.seed.wrapping_add(0x9e3779b97f4a7c15);
+        let mut priority = self.seed;
+        priority = (priority ^ (priority >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        priority = (priority ^ (priority >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+        let node = Node {
+            value: Some(value),
+            start: start.0,
+            start_gravity: start.1,
+            end: end.0,
+            end_gravity: end.1,
+            own_end_gravity: end.1,
+            held: NIL,
+            max_end: key(end.0, end.1),
+            shift: 0,
+            priority: priority ^ (priority >> 31),
+            left: NIL,
+            right: NIL,
+            parent: NIL,
+        };
+
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            },
+        };
+        self.hold_end(slot);
+
+        let root = self.root;
+        let (left, right) = self.split(root, key(start.0, start.1), true);
+        let left = self.merge(left, slot);
+        let root = self.merge(left, right);
+        self.set_root(root);
+        slot
+    }
+
+    /// Remove the mark in `slot`, returning its value, or `None` if the slot is free.
+    pub fn remove(&mut self, slot: usize) -> Option<V> {
+        self.value(slot)?;
+
+        // Apply the shifts above the node before taking it out from under them.
+        let mut path = Vec::new();
+        let mut node = slot;
+        while node != NIL {
+            path.push(node);
+            node = self.nodes[node].parent;
+        }
+        for &node in path.iter().rev() {
+            self.push(node);
+        }
+
+        let (left, right, parent) = {
+            let node = &self.nodes[slot];
+            (node.left, node.right, node.parent)
+        };
+        let merged = self.merge(left, right);
+
+        if parent == NIL {
+            self.set_root(merged);
+        } else if self.nodes[parent].left == slot {
+            self.set_left(parent, merged);
+        } else {
+            self.set_right(parent, merged);
+        }
+
+        // The largest ends above may have been the removed one.
+        let mut above = parent;
+        while above != NIL {
+            self.update(above);
+            above = self.nodes[above].parent;
+        }
+
+        let held = self.nodes[slot].held;
+        if held != NIL {
+            self.held.as_mut().unwrap().remove(held);
+        }
+
+        self.free.push(slot);
+        self.nodes[slot].value.take()
+    }
+
+    /// The value of the mark in `slot`, unless the slot is free.
+    pub fn value(&self, slot: usize) -> Option<&V> {
+        self.nodes.get(slot).and_then(|node| node.value.as_ref())
+    }
+
+    pub fn value_mut(&mut self, slot: usize) -> Option<&mut V> {
+        self.nodes.get_mut(slot).and_then(|node| node.value.as_mut())
+    }
+
+    /// The value and range of the mark in `slot`, unless the slot is free.
+    ///
+    /// Takes `O(log m)` time.
+    pub fn get(&self, slot: usize) -> Option<(&V, ops::Range<usize>)> {
+        let value = self.value(slot)?;
+        let node = &self.nodes[slot];
+
+        let mut shift = 0;
+        let mut above = node.parent;
+        while above != NIL {
+            shift += self.nodes[above].shift;
+            above = self.nodes[above].parent;
+        }
+
+        Some((value, (node.start as isize + shift) as usize .. (node.end as isize + shift) as usize))
+    }
+
+    /// The values of all marks along with their slots, in no particular order.
+    pub fn values(&self) -> impl Iterator<Item=(usize, &V)> {
+        self.nodes.iter().enumerate().filter_map(|(slot, node)| node.value.as_ref().map(|value| (slot, value)))
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item=&mut V> {
+        self.nodes.iter_mut().filter_map(|node| node.value.as_mut())
+    }
+
+    /// The slots and ranges of the non-empty marks that start before `to` and end after `from`, ordered by start.
+    ///
+    /// Takes `O((k + 1) log m)` time for `k` marks found.
+    pub fn overlapping(&self, from: usize, to: usize) -> Vec<(usize, ops::Range<usize>)> {
+        let mut found = Vec::new();
+        self.find(self.root, 0, from, to, &mut found);
+        found
+    }
+
+    /// Move the marks after `idx`, where `length` elements were inserted.
+    pub fn inserted(&mut self, idx: usize, length: usize) {
+        let root = self.root;
+        let (left, right) = self.split(root, key(idx, Gravity::Right), false);
+        self.shift(right, length as isize);
+
+        if let Some(ref mut held) = self.held {
+            held.inserted(idx, length);
+        }
+
+        // The marks starting before `idx` may still end after it.
+        self.move_ends(left, key(idx, Gravity::Right), &|offset, gravity| inserted(offset, gravity, idx, length));
+
+        let root = self.merge(left, right);
+        self.set_root(root);
+    }
+
+    /// Move the marks after `to`, where the elements from `from` were removed, and the marks starting inside to `from`, calling `f` with each of those.
+    ///
+    /// An end is inside if the element on the side its gravity sticks to was removed.
+    pub fn removed<F>(&mut self, from: usize, to: usize, mut f: F) where F: FnMut(&mut V) {
+        if from == to {
+            return;
+        }
+
+        let root = self.root;
+        let (left, rest) = self.split(root, key(from, Gravity::Right), false);
+        let (inside, right) = self.split(rest, key(to, Gravity::Right), false);
+        self.shift(right, -((to - from) as isize));
+
+        if let Some(ref mut held) = self.held {
+            held.removed(from, to, (|_| ()) as fn(&mut ()));
+        }
+
+        let mut slots = Vec::new();
+        self.collect(inside, &mut slots);
+
+        // All of them start at `from` now, where those with `Gravity::Left` must come first.
+        for &slot in &slots {
+            {
+                let node = &mut self.nodes[slot];
+                node.start = from;
+                node.end = removed(node.end, node.end_gravity, from, to);
+                f(node.value.as_mut().unwrap());
+            }
+
+            // The actual end may have been removed along with the start, and no longer come before it.
+            if self.nodes[slot].held != NIL {
+                self.release_end(slot);
+                self.hold_end(slot);
+            }
+        }
+        let (mut lefts, rights): (Vec<usize>, Vec<usize>) = slots.into_iter().partition(|&slot| self.nodes[slot].start_gravity == Gravity::Left);
+        lefts.extend(rights);
+        let inside = self.build(&lefts);
+
+        self.move_ends(left, (from + 1, false), &|offset, gravity| removed(offset, gravity, from, to));
+
+        let left = self.merge(left, inside);
+        let root = self.merge(left, right);
+        self.set_root(root);
+    }
+
+    /// Move every mark to the range returned by `f`, which is given its slot, value and start.
+    ///
+    /// Takes `O(m log m)` time.
+    pub fn rebuild<F>(&mut self, mut f: F) where F: FnMut(usize, &mut V, usize) -> (usize, usize) {
+        let mut slots = Vec::new();
+        let root = self.root;
+        self.collect(root, &mut slots);
+
+        self.held = None;
+        for &slot in &slots {
+            {
+                let node = &mut self.nodes[slot];
+                let (start, end) = f(slot, node.value.as_mut().unwrap(), node.start);
+                node.start = start;
+                node.end = end;
+                node.end_gravity = node.own_end_gravity;
+                node.held = NIL;
+            }
+            self.hold_end(slot);
+        }
+        // Stable, so marks that stay at the same offset keep their order.
+        slots.sort_by_key(|&slot| key(self.nodes[slot].start, self.nodes[slot].start_gravity));
+
+        let root = self.build(&slots);
+        self.set_root(root);
+    }
+
+    /// Hold the end of the mark in `slot` at its start if it comes before it, keeping the actual end in `held`.
+    /// The mark must not be in the treap, or have its ends moved by pending shifts.
+    fn hold_end(&mut self, slot: usize) {
+        let (start, start_gravity, end, end_gravity) = {
+            let node = &self.nodes[slot];
+            (node.start, node.start_gravity, node.end, node.end_gravity)
+        };
+
+        if key(end, end_gravity) < key(start, start_gravity) {
+            let held = self.held.get_or_insert_with(|| Box::new(Marks::new())).insert((end, end_gravity), (end, end_gravity), ());
+            let node = &mut self.nodes[slot];
+            node.end = start;
+            node.end_gravity = start_gravity;
+            node.held = held;
+        }
+        self.nodes[slot].max_end = key(self.nodes[slot].end, self.nodes[slot].end_gravity);
+    }
+
+    /// Move the held end of the mark in `slot` back to its actual end.
+    fn release_end(&mut self, slot: usize) {
+        let held = self.held.as_mut().unwrap();
+        let end = held.get(self.nodes[slot].held).unwrap().1.start;
+        held.remove(self.nodes[slot].held);
+
+        let node = &mut self.nodes[slot];
+        node.end = end;
+        node.end_gravity = node.own_end_gravity;
+        node.held = NIL;
+    }
+
+    fn set_root(&mut self, root: usize) {
+        self.root = root;
+        if root != NIL {
+            self.nodes[root].parent = NIL;
+        }
+    }
+
+    fn set_left(&mut self, node: usize, left: usize) {
+        self.nodes[node].left = left;
+        if left != NIL {
+            self.nodes[left].parent = node;
+        }
+    }
+
+    fn set_right(&mut self, node: usize, right: usize) {
+        self.nodes[node].right = right;
+        if right != NIL {
+            self.nodes[right].parent = node;
+        }
+    }
+
+    /// Compute the largest end below `node` again, after its children or its own end changed.
+    fn update(&mut self, node: usize) {
+        let max_end = {
+            let this = &self.nodes[node];
+            let mut max_end = key(this.end, this.end_gravity);
+
+            for &child in &[this.left, this.right] {
+                if child != NIL {
+                    let (offset, right) = self.nodes[child].max_end;
+                    max_end = max_end.max(((offset as isize + this.shift) as usize, right));
+                }
+            }
+            max_end
+        };
+
+        self.nodes[node].max_end = max_end;
+    }
+
+    /// Shift the marks below and including `node`.
+    fn shift(&mut self, node: usize, by: isize) {
+        if node != NIL && by != 0 {
+            let node = &mut self.nodes[node];
+            node.start = (node.start as isize + by) as usize;
+            node.end = (node.end as isize + by) as usize;
+            node.max_end.0 = (node.max_end.0 as isize + by) as usize;
+            node.shift += by;
+        }
+    }
+
+    /// Apply the pending shift of `node` to its children.
+    fn push(&mut self, node: usize) {
+        let (shift, left, right) = {
+            let node = &self.nodes[node];
+            (node.shift, node.left, node.right)
+        };
+
+        if shift != 0 {
+            self.shift(left, shift);
+            self.shift(right, shift);
+            self.nodes[node].shift = 0;
+        }
+    }
+
+    /// Split the treap under `node` into the marks starting before `at` and the rest, or those starting at it as well if `inclusive`.
+    fn split(&mut self, node: usize, at: (usize, bool), inclusive: bool) -> (usize, usize) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+
+        self.push(node);
+        let (start, left, right) = {
+            let node = &self.nodes[node];
+            (key(node.start, node.start_gravity), node.left, node.right)
+        };
+
+        let parts = if start < at || (inclusive && start == at) {
+            let (rest, after) = self.split(right, at, inclusive);
+            self.set_right(node, rest);
+            (node, after)
+        } else {
+            let (before, rest) = self.split(left, at, inclusive);
+            self.set_left(node, rest);
+            (before, node)
+        };
+
+        self.update(node);
+        parts
+    }
+
+    /// Join the treaps under `left` and `right`, where all marks of `left` come first.
+    fn merge(&mut self, left: usize, right: usize) -> usize {
+        if left == NIL {
+            return right;
+        }
+        if right == NIL {
+            return left;
+        }
+
+        let root = if self.nodes[left].priority > self.nodes[right].priority {
+            self.push(left);
+            let child = self.nodes[left].right;
+            let merged = self.merge(child, right);
+            self.set_right(left, merged);
+            left
+        } else {
+            self.push(right);
+            let child = self.nodes[right].left;
+            let merged = self.merge(left, child);
+            self.set_left(right, merged);
+            right
+        };
+
+        self.update(root);
+        root
+    }
+
+    /// Move the ends below `node` that are at or after `at` to where `f` returns.
+    fn move_ends<F>(&mut self, node: usize, at: (usize, bool), f: &F) where F: Fn(usize, Gravity) -> usize {
+        if node == NIL || self.nodes[node].max_end < at {
+            return;
+        }
+
+        self.push(node);
+        let (left, right) = (self.nodes[node].left, self.nodes[node].right);
+        self.move_ends(left, at, f);
+        self.move_ends(right, at, f);
+
+        {
+            let node = &mut self.nodes[node];
+            if key(node.end, node.end_gravity) >= at {
+                node.end = f(node.end, node.end_gravity);
+            }
+        }
+        self.update(node);
+    }
+
+    /// Push the marks below `node` that overlap the range from `from` to `to` onto `found`, where `shift` is pending from above.
+    fn find(&self, node: usize, shift: isize, from: usize, to: usize, found: &mut Vec<(usize, ops::Range<usize>)>) {
+        if node == NIL || (self.nodes[node].max_end.0 as isize + shift) as usize <= from {
+            return;
+        }
+
+        let node_ref = &self.nodes[node];
+        let start = (node_ref.start as isize + shift) as usize;
+        let end = (node_ref.end as isize + shift) as usize;
+
+        self.find(node_ref.left, shift + node_ref.shift, from, to, found);
+        if start < to {
+            if start < end && from < end {
+                found.push((node, start .. end));
+            }
+            self.find(node_ref.right, shift + node_ref.shift, from, to, found);
+        }
+    }
+
+    /// Push the slots below `node` onto `slots` in order, applying all pending shifts.
+    fn collect(&mut self, node: usize, slots: &mut Vec<usize>) {
+        if node != NIL {
+            self.push(node);
+            let (left, right) = (self.nodes[node].left, self.nodes[node].right);
+            self.collect(left, slots);
+            slots.push(node);
+            self.collect(right, slots);
+        }
+    }
+
+    /// Build a treap of the marks in `slots`, which are in order and have no pending shifts, in `O(n)` time.
+    fn build(&mut self, slots: &[usize]) -> usize {
+        let mut spine: Vec<usize> = Vec::new();
+
+        for &slot in slots {
+            let mut left = NIL;
+            while spine.last().is_some_and(|&top| self.nodes[top].priority < self.nodes[slot].priority) {
+                left = spine.pop().unwrap();
+            }
+
+            self.nodes[slot].right = NIL;
+            self.set_left(slot, left);
+            if let Some(&top) = spine.last() {
+                self.set_right(top, slot);
+            }
+            spine.push(slot);
+        }
+
+        let root = spine.first().map_or(NIL, |&root| root);
+        if root != NIL {
+            self.nodes[root].parent = NIL;
+            self.update_all(root);
+        }
+        root
+    }
+
+    /// Compute the largest ends below `node` again.
+    fn update_all(&mut self, node: usize) {
+        if node != NIL {
+            let (left, right) = (self.nodes[node].left, self.nodes[node].right);
+            self.update_all(left);
+            self.update_all(right);
+            self.update(node);
+        }
+    }
+}
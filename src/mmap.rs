@@ -0,0 +1,80 @@
+//! Memory-mapped files as the source of a `PieceTable`.
+//!
+//! Other processes can change or truncate a file while it is mapped, which would change the elements under the table,
+//! or fault when reading past the new end. So the file is first copied to a file that only this process can reach, which is mapped instead.
+//! The copy is made next to the file where possible, so file systems that share blocks between copies make it in constant time,
+//! and only the parts that are read are loaded into memory either way.
+//! Edits go to the add buffer as usual and never touch either file.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use memmap2::Mmap;
+
+/// The number of copies made so far, which keeps the names of their files apart.
+static COPIES: AtomicUsize = AtomicUsize::new(0);
+
+/// A read-only memory map of a private copy of a file, which can be used as the source of a `PieceTable`.
+///
+/// Changing or truncating the file after opening it does not affect the source.
+/// Cloning the source shares the mapping.
+#[derive(Debug, Clone)]
+pub struct MmapSource {
+    map: Arc<Mmap>,
+}
+
+impl MmapSource {
+    /// Open the file at `path`, copy it and map the copy into memory.
+    ///
+    /// Takes time proportional to the size of the file, unless the file system shares blocks between copies.
+    /// Returns an error of kind `UnexpectedEof` if the file was truncated while being copied.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MmapSource> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+
+        let mut copy = private_file(path)?;
+        let copied = io::copy(&mut file, &mut copy)?;
+        if copied < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("{} truncated from {} to {} bytes while opening it", path.display(), len, copied)));
+        }
+
+        // Nothing else can reach the copy, so it stays unchanged while it is mapped.
+        let map = unsafe { Mmap::map(&copy)? };
+
+        Ok(MmapSource {
+            map: Arc::new(map),
+        })
+    }
+}
+
+impl AsRef<[u8]> for MmapSource {
+    fn as_ref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+/// Create a file that nothing else can reach, by removing it right away, next to `path` if possible and in the temporary directory otherwise.
+fn private_file(path: &Path) -> io::Result<File> {
+    let name = format!(".{}.{}.{}", path.file_name().map_or("piecetable".into(), |name| name.to_string_lossy()),
+                       process::id(), COPIES.fetch_add(1, Ordering::Relaxed));
+    let next_to = path.parent().map(|dir| dir.join(&name));
+
+    let mut error = None;
+    for copy_path in next_to.into_iter().chain(Some(env::temp_dir().join(&name))) {
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&copy_path) {
+            Ok(copy) => {
+                fs::remove_file(&copy_path)?;
+                return Ok(copy);
+            },
+            Err(err) => error = Some(err),
+        }
+    }
+    Err(error.unwrap())
+}
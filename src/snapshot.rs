@@ -0,0 +1,113 @@
+//! Read-only snapshots of a `PieceTable`, for reading it on other threads while it is edited.
+//!
+//! A snapshot holds a clone of the piece tree, whose nodes are shared, and of the chunks of the add buffer, whose elements are shared.
+//! Edits to the table copy the nodes they change and append after the elements the snapshot holds, so the snapshot never sees them.
+
+use std::collections::Bound;
+use std::marker::PhantomData;
+use std::ops::{Index, RangeBounds};
+
+use {bounds, Chunks, Iter, Range};
+use Buffer::*;
+use adds::Adds;
+use tree::Tree;
+
+/// A read-only view of the elements of a `PieceTable` when `PieceTable::snapshot` was called.
+///
+/// It is `Send` and `Sync` if the elements and the source are.
+#[derive(Debug)]
+pub struct Snapshot<'a, T: 'a, S = &'a [T]> {
+    original: Option<S>,
+    adds: Adds<T>,
+    pieces: Tree,
+    length: usize,
+    marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: 'a, S: 'a + AsRef<[T]>> Snapshot<'a, T, S> {
+    pub(crate) fn new(original: Option<S>, adds: Adds<T>, pieces: Tree, length: usize) -> Snapshot<'a, T, S> {
+        Snapshot {
+            original: original,
+            adds: adds,
+            pieces: pieces,
+            length: length,
+            marker: PhantomData,
+        }
+    }
+
+    /// The number of elements in the snapshot.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns `true` if the snapshot contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Return an iterator over all elements, behaving like `PieceTable::iter`.
+    pub fn iter<'s>(&'s self) -> Iter<'s, T> {
+        Iter::new(self.chunks())
+    }
+
+    /// Return an iterator over the bound range, behaving like `PieceTable::range`.
+    pub fn range<'s>(&'s self, min: Bound<usize>, max: Bound<usize>) -> Range<'s, T> {
+        let (from, to) = bounds((min, max), self.length);
+        let to = to.min(self.length);
+        let from = from.min(to);
+
+        Range {
+            iter: Iter::new(self.chunks_in(from .. to)),
+        }
+    }
+
+    /// Return an iterator over the elements as slices, behaving like `PieceTable::chunks`.
+    pub fn chunks<'s>(&'s self) -> Chunks<'s, T> {
+        self.chunks_in(..)
+    }
+
+    /// Return an iterator over the elements in `range` as slices, behaving like `PieceTable::chunks_in`.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds.
+    pub fn chunks_in<'s, R>(&'s self, range: R) -> Chunks<'s, T> where R: RangeBounds<usize> {
+        let (from, to) = bounds(range, self.length);
+        assert!(from <= to && to <= self.length);
+
+        Chunks::new(self.original_buffer(), &self.adds, self.pieces.clone(), from, to)
+    }
+
+    fn original_buffer(&self) -> &[T] {
+        self.original.as_ref().map_or(&[], |original| original.as_ref())
+    }
+}
+
+impl<'a, T, S: Clone> Clone for Snapshot<'a, T, S> {
+    fn clone(&self) -> Snapshot<'a, T, S> {
+        Snapshot {
+            original: self.original.clone(),
+            adds: self.adds.clone(),
+            pieces: self.pieces.clone(),
+            length: self.length,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T, S: 'a + AsRef<[T]>> Index<usize> for Snapshot<'a, T, S> {
+    type Output = T;
+
+    /// Note: Reading an index takes `O(log p)` time, use iterators for fast sequential access.
+    fn index(&self, idx: usize) -> &T {
+        match self.pieces.locate(idx) {
+            Some((piece_idx, offset)) => {
+                let piece = self.pieces.get(piece_idx);
+                match piece.buffer {
+                    Add => self.adds.get(piece.start + offset),
+                    Original => &self.original_buffer()[piece.start + offset],
+                }
+            },
+            None => panic!("Snapshot out of bounds: {}", idx),
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! A text table storing UTF-8 encoded text in a `PieceTable<u8>`.
+//!
+//! Text is only ever inserted as whole strings at character boundaries, and only whole characters are removed.
+//! Every piece therefore holds valid UTF-8, so the text can be read as string slices without decoding across pieces.
+
+use std::fmt;
+use std::ops::RangeBounds;
+use std::str;
+
+use {Chunks, Edit, InputEdit, PieceTable, Subscription};
+
+/// A UTF-8 text table, which is a `PieceTable<u8>` that only accepts strings.
+///
+/// Offsets are byte offsets, like for `String`, and must lie on character boundaries.
+#[derive(Debug, Clone, Hash)]
+pub struct TextTable<'a, S = &'a [u8]> {
+    table: PieceTable<'a, u8, S>,
+}
+
+/// Struct for iterating the characters of a `TextTable`.
+pub struct Chars<'a> {
+    chunks: Chunks<'a, u8>,
+    front: str::Chars<'a>,
+    back: str::Chars<'a>,
+}
+
+/// Struct for iterating the characters of a `TextTable` along with their byte offsets.
+pub struct CharIndices<'a> {
+    chars: Chars<'a>,
+    front: usize,
+    back: usize,
+}
+
+impl<'a> TextTable<'a> {
+
+    /// Construct a new, empty `TextTable`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::TextTable;
+    /// let text = TextTable::new();
+    /// assert!(text.is_empty());
+    /// ```
+    pub fn new() -> TextTable<'a> {
+        Default::default()
+    }
+}
+
+impl<'a, S: 'a + AsRef<[u8]>> TextTable<'a, S> {
+
+    /// Assign a read-only source to an existing `TextTable`.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::TextTable;
+    /// let text = TextTable::new().src("hello");
+    /// assert_eq!("hello", text.to_string());
+    /// ```
+    pub fn src(self, src: &'a str) -> TextTable<'a> {
+        TextTable {
+            table: self.table.src(src.as_bytes()),
+        }
+    }
+
+    /// Assign a read-only source of any type that can be viewed as bytes, such as `String` or a memory-mapped file.
+    ///
+    /// The source is checked to be valid UTF-8 in `O(n)` time, returning an error if it is not.
+    pub fn source<U>(self, src: U) -> Result<TextTable<'a, U>, str::Utf8Error> where U: 'a + AsRef<[u8]> {
+        str::from_utf8(src.as_ref())?;
+
+        Ok(TextTable {
+            table: self.table.source(src),
+        })
+    }
+
+    /// Start recording undo history, see `PieceTable::history`.
+    pub fn history(self) -> TextTable<'a, S> {
+        TextTable {
+            table: self.table.history(),
+        }
+    }
+
+    /// Start indexing line breaks, see `PieceTable::line_index`.
+    pub fn line_index(self) -> TextTable<'a, S> {
+        TextTable {
+            table: self.table.line_index(),
+        }
+    }
+
+    /// Revert to the parent of the current revision, see `PieceTable::undo`.
+    pub fn undo(&mut self) -> bool {
+        self.table.undo()
+    }
+
+    /// Reapply the revision most recently undone, see `PieceTable::redo`.
+    pub fn redo(&mut self) -> bool {
+        self.table.redo()
+    }
+
+    /// Start recording edits for a new subscriber, with offsets in bytes, see `PieceTable::subscribe`.
+    pub fn subscribe(&mut self) -> Subscription {
+        self.table.subscribe()
+    }
+
+    /// Stop recording edits for `subscription`, see `PieceTable::unsubscribe`.
+    pub fn unsubscribe(&mut self, subscription: Subscription) -> bool {
+        self.table.unsubscribe(subscription)
+    }
+
+    /// Return the edits made since `subscription` last polled, see `PieceTable::poll_edits`.
+    pub fn poll_edits(&mut self, subscription: Subscription) -> &[Edit] {
+        self.table.poll_edits(subscription)
+    }
+
+    /// Return the edits made since `subscription` last polled along with their rows and columns, see `PieceTable::poll_input_edits`.
+    pub fn poll_input_edits(&mut self, subscription: Subscription) -> Vec<InputEdit> {
+        self.table.poll_input_edits(subscription)
+    }
+
+    /// The bytes from `idx` to the end of the piece containing it, see `PieceTable::chunk_at`.
+    /// Pieces hold whole characters, so the chunk is valid UTF-8 if `idx` is a character boundary.
+    pub fn chunk_at(&self, idx: usize) -> &[u8] {
+        self.table.chunk_at(idx)
+    }
+
+    /// The underlying table of bytes, which gives access to everything that does not edit the text.
+    pub fn as_table(&self) -> &PieceTable<'a, u8, S> {
+        &self.table
+    }
+
+    /// The length of the text in bytes.
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Returns `true` if the text is empty.
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// Whether `idx` is the first byte of a character or the end of the text, in `O(log p)` time.
+    pub fn is_char_boundary(&self, idx: usize) -> bool {
+        if idx == 0 || idx == self.len() {
+            true
+        } else if idx > self.len() {
+            false
+        } else {
+            // Continuation bytes look like 0b10xxxxxx.
+            self.table[idx] & 0xC0 != 0x80
+        }
+    }
+
+    fn assert_char_boundary(&self, idx: usize) {
+        assert!(self.is_char_boundary(idx), "byte index {} is not a char boundary", idx);
+    }
+
+    /// Insert `string` at byte offset `idx`.
+    ///
+    /// # Panics
+    /// Panics if `idx` is not a character boundary or past the end.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::TextTable;
+    /// let mut text = TextTable::new().src("grüß");
+    /// text.insert_str(6, "e dich");
+    /// assert_eq!("grüße dich", text.to_string());
+    /// ```
+    pub fn insert_str(&mut self, idx: usize, string: &str) {
+        self.assert_char_boundary(idx);
+        self.table.insert_slice(idx, string.as_bytes());
+    }
+
+    /// Insert `ch` at byte offset `idx`.
+    ///
+    /// # Panics
+    /// Panics if `idx` is not a character boundary or past the end.
+    pub fn insert(&mut self, idx: usize, ch: char) {
+        self.insert_str(idx, ch.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Append `string` to the end.
+    pub fn push_str(&mut self, string: &str) {
+        let end = self.len();
+        self.table.insert_slice(end, string.as_bytes());
+    }
+
+    /// Append `ch` to the end.
+    pub fn push(&mut self, ch: char) {
+        self.push_str(ch.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Remove and return the character at byte offset `idx`.
+    ///
+    /// # Panics
+    /// Panics if `idx` is not a character boundary or not before the end.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::TextTable;
+    /// let mut text = TextTable::new().src("grüß");
+    /// assert_eq!('ü', text.remove(2));
+    /// assert_eq!("grß", text.to_string());
+    /// ```
+    pub fn remove(&mut self, idx: usize) -> char {
+        assert!(idx < self.len());
+        self.assert_char_boundary(idx);
+
+        let ch = self.char_at(idx);
+        self.table.remove_range(idx .. idx + ch.len_utf8());
+        ch
+    }
+
+    /// Remove the text in the byte range `range`.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds or does not start and end on character boundaries.
+    pub fn remove_range<R>(&mut self, range: R) where R: RangeBounds<usize> {
+        self.replace_range(range, "");
+    }
+
+    /// Replace the text in the byte range `range` with `string`, recorded as a single revision.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds or does not start and end on character boundaries.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::TextTable;
+    /// let mut text = TextTable::new().src("hello world");
+    /// text.replace_range(6.., "there");
+    /// assert_eq!("hello there", text.to_string());
+    /// ```
+    pub fn replace_range<R>(&mut self, range: R, string: &str) where R: RangeBounds<usize> {
+        let (from, to) = self.table.bounds(range);
+        assert!(from <= to && to <= self.len());
+        self.assert_char_boundary(from);
+        self.assert_char_boundary(to);
+
+        self.table.splice(from .. to, string.bytes());
+    }
+
+    /// The character starting at byte offset `idx`, which is a character boundary before the end.
+    fn char_at(&self, idx: usize) -> char {
+        let chunk = self.table.chunks_in(idx..).next().unwrap();
+        let width = match chunk[0] {
+            0x00 ..= 0x7F => 1,
+            0xC0 ..= 0xDF => 2,
+            0xE0 ..= 0xEF => 3,
+            _ => 4,
+        };
+
+        chunk_str(&chunk[.. width]).chars().next().unwrap()
+    }
+
+    /// Return an iterator over the characters of the text.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::TextTable;
+    /// let mut text = TextTable::new().src("ab");
+    /// text.insert(1, 'ü');
+    /// assert_eq!(vec!['b', 'ü', 'a'], text.chars().rev().collect::<Vec<char>>());
+    /// ```
+    pub fn chars(&'a self) -> Chars<'a> {
+        Chars {
+            chunks: self.table.chunks(),
+            front: "".chars(),
+            back: "".chars(),
+        }
+    }
+
+    /// Return an iterator over the characters of the text and their byte offsets.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::TextTable;
+    /// let text = TextTable::new().src("aüb");
+    /// assert_eq!(vec![(0, 'a'), (1, 'ü'), (3, 'b')], text.char_indices().collect::<Vec<(usize, char)>>());
+    /// ```
+    pub fn char_indices(&'a self) -> CharIndices<'a> {
+        CharIndices {
+            chars: self.chars(),
+            front: 0,
+            back: self.len(),
+        }
+    }
+
+    /// The number of characters before byte offset `idx`.
+    ///
+    /// Takes `O(n)` time, but only the bytes before `idx` are read, a piece at a time.
+    ///
+    /// # Panics
+    /// Panics if `idx` is not a character boundary or past the end.
+    ///
+    /// # Example
+    /// ```
+    /// use piecetable::TextTable;
+    /// let text = TextTable::new().src("grüße");
+    /// assert_eq!(4, text.byte_to_char(6));
+    /// assert_eq!(6, text.char_to_byte(4));
+    /// ```
+    pub fn byte_to_char(&self, idx: usize) -> usize {
+        self.assert_char_boundary(idx);
+
+        self.table.chunks_in(..idx)
+            .map(|chunk| chunk.iter().filter(|&&b| b & 0xC0 != 0x80).count())
+            .sum()
+    }
+
+    /// The byte offset of character number `char_idx`, counting from zero, or the length of the text if `char_idx` is the number of characters.
+    ///
+    /// Takes `O(n)` time, but only the bytes before the character are read, a piece at a time.
+    ///
+    /// # Panics
+    /// Panics if `char_idx` is larger than the number of characters.
+    pub fn char_to_byte(&self, mut char_idx: usize) -> usize {
+        let mut offset = 0;
+
+        for chunk in self.table.chunks() {
+            let string = chunk_str(chunk);
+            match string.char_indices().nth(char_idx) {
+                Some((idx, _)) => return offset + idx,
+                None => {
+                    char_idx -= string.chars().count();
+                    offset += chunk.len();
+                },
+            }
+        }
+
+        assert!(char_idx == 0, "char index out of bounds");
+        offset
+    }
+}
+
+/// Every piece holds whole characters, so chunks can be read as strings.
+fn chunk_str(chunk: &[u8]) -> &str {
+    str::from_utf8(chunk).expect("piece does not hold whole characters")
+}
+
+impl<'a, S: 'a + AsRef<[u8]>> fmt::Display for TextTable<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.table.chunks() {
+            f.write_str(chunk_str(chunk))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, S> Default for TextTable<'a, S> {
+    fn default() -> TextTable<'a, S> {
+        TextTable {
+            table: Default::default(),
+        }
+    }
+}
+
+impl<'a> Iterator for Chars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        loop {
+            if let Some(ch) = self.front.next() {
+                return Some(ch);
+            }
+
+            match self.chunks.next() {
+                Some(chunk) => self.front = chunk_str(chunk).chars(),
+                None => return self.back.next(),
+            }
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for Chars<'a> {
+    fn next_back(&mut self) -> Option<char> {
+        loop {
+            if let Some(ch) = self.back.next_back() {
+                return Some(ch);
+            }
+
+            match self.chunks.next_back() {
+                Some(chunk) => self.back = chunk_str(chunk).chars(),
+                None => return self.front.next_back(),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for CharIndices<'a> {
+    type Item = (usize, char);
+
+    fn next(&mut self) -> Option<(usize, char)> {
+        self.chars.next().map(|ch| {
+            let idx = self.front;
+            self.front += ch.len_utf8();
+            (idx, ch)
+        })
+    }
+}
+
+impl<'a> DoubleEndedIterator for CharIndices<'a> {
+    fn next_back(&mut self) -> Option<(usize, char)> {
+        self.chars.next_back().map(|ch| {
+            self.back -= ch.len_utf8();
+            (self.back, ch)
+        })
+    }
+}
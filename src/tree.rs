@@ -0,0 +1,541 @@
+//! A persistent AVL tree storing the pieces of a `PieceTable` in order.
+//!
+//! Every node caches the number of elements, pieces and line breaks in its subtree, so offsets, piece indices and lines can be located in `O(log p)` time.
+//! Nodes are reference counted and copied on write, which keeps clones of a tree cheap.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use Piece;
+
+type Link = Option<Arc<Node>>;
+
+#[derive(Clone)]
+struct Node {
+    piece: Piece,
+    left: Link,
+    right: Link,
+    height: usize,
+    len: usize,
+    count: usize,
+    breaks: usize,
+}
+
+/// The ordered sequence of pieces making up a `PieceTable`.
+#[derive(Clone, Default)]
+pub(crate) struct Tree {
+    root: Link,
+}
+
+fn height(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn len(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.len)
+}
+
+fn count(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.count)
+}
+
+fn breaks(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.breaks)
+}
+
+impl Node {
+    fn leaf(piece: Piece) -> Node {
+        Node {
+            piece: piece,
+            left: None,
+            right: None,
+            height: 1,
+            len: piece.length,
+            count: 1,
+            breaks: piece.breaks,
+        }
+    }
+
+    fn update(&mut self) {
+        self.height = 1 + cmp::max(height(&self.left), height(&self.right));
+        self.len = len(&self.left) + self.piece.length + len(&self.right);
+        self.count = count(&self.left) + 1 + count(&self.right);
+        self.breaks = breaks(&self.left) + self.piece.breaks + breaks(&self.right);
+    }
+
+    fn balance(&self) -> isize {
+        height(&self.left) as isize - height(&self.right) as isize
+    }
+}
+
+fn rotate_right(link: &mut Link) {
+    let mut node = link.take().unwrap();
+    let mut left = Arc::make_mut(&mut node).left.take().unwrap();
+
+    {
+        let node = Arc::make_mut(&mut node);
+        node.left = Arc::make_mut(&mut left).right.take();
+        node.update();
+    }
+
+    let left_mut = Arc::make_mut(&mut left);
+    left_mut.right = Some(node);
+    left_mut.update();
+
+    *link = Some(left);
+}
+
+fn rotate_left(link: &mut Link) {
+    let mut node = link.take().unwrap();
+    let mut right = Arc::make_mut(&mut node).right.take().unwrap();
+
+    {
+        let node = Arc::make_mut(&mut node);
+        node.right = Arc::make_mut(&mut right).left.take();
+        node.update();
+    }
+
+    let right_mut = Arc::make_mut(&mut right);
+    right_mut.left = Some(node);
+    right_mut.update();
+
+    *link = Some(right);
+}
+
+fn rebalance(link: &mut Link) {
+    let balance = match *link {
+        Some(ref mut node) => {
+            Arc::make_mut(node).update();
+            node.balance()
+        },
+        None => return,
+    };
+
+    if balance > 1 {
+        let node = Arc::make_mut(link.as_mut().unwrap());
+        if node.left.as_ref().unwrap().balance() < 0 {
+            rotate_left(&mut node.left);
+        }
+        rotate_right(link);
+    } else if balance < -1 {
+        let node = Arc::make_mut(link.as_mut().unwrap());
+        if node.right.as_ref().unwrap().balance() > 0 {
+            rotate_right(&mut node.right);
+        }
+        rotate_left(link);
+    }
+}
+
+fn insert(link: &mut Link, idx: usize, piece: Piece) {
+    match *link {
+        None => {
+            *link = Some(Arc::new(Node::leaf(piece)));
+            return;
+        },
+        Some(ref mut node) => {
+            let node = Arc::make_mut(node);
+            let left = count(&node.left);
+            if idx <= left {
+                insert(&mut node.left, idx, piece);
+            } else {
+                insert(&mut node.right, idx - left - 1, piece);
+            }
+        },
+    }
+
+    rebalance(link);
+}
+
+fn remove_first(link: &mut Link) -> Piece {
+    let piece = {
+        let node = Arc::make_mut(link.as_mut().unwrap());
+        if node.left.is_some() {
+            Some(remove_first(&mut node.left))
+        } else {
+            None
+        }
+    };
+
+    match piece {
+        Some(piece) => {
+            rebalance(link);
+            piece
+        },
+        None => {
+            let node = unwrap(link.take().unwrap());
+            *link = node.right;
+            node.piece
+        },
+    }
+}
+
+fn remove(link: &mut Link, idx: usize) -> Piece {
+    let (piece, unlink) = {
+        let node = Arc::make_mut(link.as_mut().unwrap());
+        let left = count(&node.left);
+
+        if idx < left {
+            (remove(&mut node.left, idx), false)
+        } else if idx > left {
+            (remove(&mut node.right, idx - left - 1), false)
+        } else if node.left.is_some() && node.right.is_some() {
+            let next = remove_first(&mut node.right);
+            (std::mem::replace(&mut node.piece, next), false)
+        } else {
+            (node.piece, true)
+        }
+    };
+
+    if unlink {
+        let node = unwrap(link.take().unwrap());
+        *link = node.left.or(node.right);
+    }
+
+    rebalance(link);
+    piece
+}
+
+fn visit<F>(link: &Link, visited: &mut HashSet<*const Node>, f: &mut F) where F: FnMut(&Piece) {
+    if let Some(ref node) = *link {
+        if visited.insert(&**node) {
+            visit(&node.left, visited, f);
+            f(&node.piece);
+            visit(&node.right, visited, f);
+        }
+    }
+}
+
+// The old nodes are kept in the map along with their copies, so their addresses are not reused while mapping.
+fn map<F>(link: &Link, copies: &mut HashMap<*const Node, (Arc<Node>, Arc<Node>)>, f: &F) -> Link where F: Fn(&Piece) -> Piece {
+    link.as_ref().map(|node| {
+        if let Some((_, copy)) = copies.get(&(&**node as *const Node)) {
+            return copy.clone();
+        }
+
+        let mut copy = Node {
+            piece: f(&node.piece),
+            left: map(&node.left, copies, f),
+            right: map(&node.right, copies, f),
+            ..**node
+        };
+        copy.update();
+
+        let copy = Arc::new(copy);
+        copies.insert(&**node, (node.clone(), copy.clone()));
+        copy
+    })
+}
+
+fn unwrap(node: Arc<Node>) -> Node {
+    Arc::try_unwrap(node).unwrap_or_else(|node| (*node).clone())
+}
+
+/// Joins `left`, `piece` and `right` in that order into a balanced tree.
+fn join(left: Link, piece: Piece, right: Link) -> Link {
+    let (hl, hr) = (height(&left), height(&right));
+
+    let mut link = if hl > hr + 1 {
+        let mut node = unwrap(left.unwrap());
+        node.right = join(node.right.take(), piece, right);
+        Some(Arc::new(node))
+    } else if hr > hl + 1 {
+        let mut node = unwrap(right.unwrap());
+        node.left = join(left, piece, node.left.take());
+        Some(Arc::new(node))
+    } else {
+        let mut node = Node::leaf(piece);
+        node.left = left;
+        node.right = right;
+        Some(Arc::new(node))
+    };
+
+    rebalance(&mut link);
+    link
+}
+
+/// Joins `left` and `right` in that order into a balanced tree.
+fn join2(mut left: Link, right: Link) -> Link {
+    if left.is_none() {
+        return right;
+    }
+
+    let last = count(&left) - 1;
+    let piece = remove(&mut left, last);
+    join(left, piece, right)
+}
+
+/// Splits off the first `idx` pieces from the rest.
+fn split(link: Link, idx: usize) -> (Link, Link) {
+    match link {
+        None => (None, None),
+        Some(node) => {
+            let node = unwrap(node);
+            let left = count(&node.left);
+
+            if idx <= left {
+                let (ll, lr) = split(node.left, idx);
+                (ll, join(lr, node.piece, node.right))
+            } else {
+                let (rl, rr) = split(node.right, idx - left - 1);
+                (join(node.left, node.piece, rl), rr)
+            }
+        },
+    }
+}
+
+fn update<F, R>(link: &mut Link, idx: usize, f: F) -> R where F: FnOnce(&mut Piece) -> R {
+    let node = Arc::make_mut(link.as_mut().unwrap());
+    let left = count(&node.left);
+
+    let result = if idx < left {
+        update(&mut node.left, idx, f)
+    } else if idx > left {
+        update(&mut node.right, idx - left - 1, f)
+    } else {
+        f(&mut node.piece)
+    };
+
+    node.update();
+    result
+}
+
+impl Tree {
+    pub fn new() -> Tree {
+        Tree { root: None }
+    }
+
+    /// The number of pieces.
+    pub fn count(&self) -> usize {
+        count(&self.root)
+    }
+
+    /// The number of elements in all pieces.
+    pub fn len(&self) -> usize {
+        len(&self.root)
+    }
+
+    /// The number of line breaks in all pieces.
+    pub fn breaks(&self) -> usize {
+        breaks(&self.root)
+    }
+
+    pub fn clear(&mut self) {
+        self.root = None;
+    }
+
+    /// Call `f` with every piece of `trees`, in order within every tree, but only once for pieces in nodes shared between them.
+    pub fn visit_shared<'a, I, F>(trees: I, mut f: F) where I: IntoIterator<Item=&'a Tree>, F: FnMut(&Piece) {
+        let mut visited = HashSet::new();
+        for tree in trees {
+            visit(&tree.root, &mut visited, &mut f);
+        }
+    }
+
+    /// Replace every piece of `trees` with `f` applied to it, keeping the nodes shared between them shared.
+    pub fn map_shared<'a, I, F>(trees: I, f: F) where I: IntoIterator<Item=&'a mut Tree>, F: Fn(&Piece) -> Piece {
+        let mut copies = HashMap::new();
+        for tree in trees {
+            tree.root = map(&tree.root, &mut copies, &f);
+        }
+    }
+
+    /// Whether both trees share the same root, in which case they are identical.
+    pub fn ptr_eq(&self, other: &Tree) -> bool {
+        match (&self.root, &other.root) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the piece at index `idx`.
+    ///
+    /// # Panics
+    /// Panics if not `idx < count`.
+    pub fn get(&self, mut idx: usize) -> &Piece {
+        let mut link = &self.root;
+
+        while let Some(ref node) = *link {
+            let left = count(&node.left);
+
+            if idx < left {
+                link = &node.left;
+            } else if idx > left {
+                idx -= left + 1;
+                link = &node.right;
+            } else {
+                return &node.piece;
+            }
+        }
+
+        panic!("piece index out of bounds: {}", idx)
+    }
+
+    pub fn last(&self) -> Option<&Piece> {
+        match self.count() {
+            0 => None,
+            n => Some(self.get(n-1)),
+        }
+    }
+
+    /// Finds the piece containing the element at offset `idx`.
+    /// Returns the index of the piece and the offset into it, or `None` if `idx` is past the end.
+    pub fn locate(&self, mut idx: usize) -> Option<(usize, usize)> {
+        let mut link = &self.root;
+        let mut piece_idx = 0;
+
+        while let Some(ref node) = *link {
+            let left = len(&node.left);
+
+            if idx < left {
+                link = &node.left;
+            } else if idx < left + node.piece.length {
+                return Some((piece_idx + count(&node.left), idx - left));
+            } else {
+                idx -= left + node.piece.length;
+                piece_idx += count(&node.left) + 1;
+                link = &node.right;
+            }
+        }
+
+        None
+    }
+
+    /// Finds the piece containing the element at offset `idx`, like `locate`.
+    /// Returns the piece, the offset of its first element and the number of line breaks before it, or `None` if `idx` is past the end.
+    pub fn locate_breaks(&self, mut idx: usize) -> Option<(&Piece, usize, usize)> {
+        let mut link = &self.root;
+        let mut offset = 0;
+        let mut before = 0;
+
+        while let Some(ref node) = *link {
+            let left = len(&node.left);
+
+            if idx < left {
+                link = &node.left;
+            } else if idx < left + node.piece.length {
+                return Some((&node.piece, offset + left, before + breaks(&node.left)));
+            } else {
+                idx -= left + node.piece.length;
+                offset += left + node.piece.length;
+                before += breaks(&node.left) + node.piece.breaks;
+                link = &node.right;
+            }
+        }
+
+        None
+    }
+
+    /// Finds the piece containing line break number `n`, counting from one.
+    /// Returns the piece, the offset of its first element and the number of line breaks before it, or `None` if there are fewer line breaks.
+    pub fn locate_break(&self, mut n: usize) -> Option<(&Piece, usize, usize)> {
+        let mut link = &self.root;
+        let mut offset = 0;
+        let mut before = 0;
+
+        while let Some(ref node) = *link {
+            let left = breaks(&node.left);
+
+            if n <= left {
+                link = &node.left;
+            } else if n <= left + node.piece.breaks {
+                return Some((&node.piece, offset + len(&node.left), before + left));
+            } else {
+                n -= left + node.piece.breaks;
+                offset += len(&node.left) + node.piece.length;
+                before += left + node.piece.breaks;
+                link = &node.right;
+            }
+        }
+
+        None
+    }
+
+    /// Inserts `piece` so that it gets index `idx`, shifting all pieces after it.
+    pub fn insert(&mut self, idx: usize, piece: Piece) {
+        assert!(idx <= self.count());
+        insert(&mut self.root, idx, piece);
+    }
+
+    pub fn push(&mut self, piece: Piece) {
+        let idx = self.count();
+        self.insert(idx, piece);
+    }
+
+    /// Removes and returns the piece at index `idx`.
+    pub fn remove(&mut self, idx: usize) -> Piece {
+        assert!(idx < self.count());
+        remove(&mut self.root, idx)
+    }
+
+    /// Splits the tree in two at index `idx`, keeping the pieces before it and returning the rest.
+    /// Takes `O(log p)` time.
+    pub fn split_off(&mut self, idx: usize) -> Tree {
+        assert!(idx <= self.count());
+        let (left, right) = split(self.root.take(), idx);
+        self.root = left;
+        Tree { root: right }
+    }
+
+    /// Moves all pieces of `other` to the end of this tree.
+    /// Takes `O(log p)` time.
+    pub fn append(&mut self, other: Tree) {
+        self.root = join2(self.root.take(), other.root);
+    }
+
+    /// Modifies the piece at index `idx` in place, keeping the cached lengths up to date.
+    pub fn update<F, R>(&mut self, idx: usize, f: F) -> R where F: FnOnce(&mut Piece) -> R {
+        assert!(idx < self.count());
+        update(&mut self.root, idx, f)
+    }
+
+    /// Returns an iterator over all pieces in order.
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        let mut iter = Iter { stack: Vec::new() };
+        iter.push_left(&self.root);
+        iter
+    }
+}
+
+/// In-order iterator over the pieces of a `Tree`.
+pub(crate) struct Iter<'a> {
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> Iter<'a> {
+    fn push_left(&mut self, mut link: &'a Link) {
+        while let Some(ref node) = *link {
+            self.stack.push(node);
+            link = &node.left;
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Piece;
+
+    fn next(&mut self) -> Option<&'a Piece> {
+        self.stack.pop().map(|node| {
+            self.push_left(&node.right);
+            &node.piece
+        })
+    }
+}
+
+impl fmt::Debug for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Hash for Tree {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.count().hash(state);
+        for piece in self.iter() {
+            piece.hash(state);
+        }
+    }
+}
@@ -9,7 +9,7 @@ extern crate piecetable;
 mod generators;
 use generators::*;
 
-use piecetable::PieceTable;
+use piecetable::{Anchor, Decoration, Decorations, Gravity, PieceTable, Point, Position, PositionEncoding, TextEdit, TextTable};
 
 const CLUSTER_RATIO: usize = 10; // Don't want to allocate needlessly
 
@@ -30,6 +30,19 @@ fn run_commands<T: Copy>(table: &mut PieceTable<T>, vec: &mut Vec<T>, commands:
     }
 }
 
+/// Turn every value divisible by four into a line break, so line breaks are common.
+fn with_line_breaks(recipe: InsertRemoveScatteredGiven<u8>) -> InsertRemoveScatteredGiven<u8> {
+    let line_break = |x: u8| if x % 4 == 0 { b'\n' } else { x };
+
+    InsertRemoveScatteredGiven {
+        data: recipe.data.iter().map(|&x| line_break(x)).collect(),
+        commands: recipe.commands.iter().map(|&cmd| match cmd {
+            Insert(idx, value) => Insert(idx, line_break(value)),
+            Remove(idx) => Remove(idx),
+        }).collect(),
+    }
+}
+
 fn table_slice_equal<T: PartialEq>(table: &PieceTable<T>, slice: &[T]) -> bool {
     let slice_vec = slice.iter().collect::<Vec<&T>>();
     let table_vec = table.iter().collect::<Vec<&T>>();
@@ -77,6 +90,12 @@ fn remove_clustered(recipe: RemoveClustered<i32>) -> bool {
                   &recipe.commands)
 }
 
+#[quickcheck]
+fn remove_forward(recipe: RemoveForward<i32>) -> bool {
+    test_commands(&mut PieceTable::with_capacity(0, recipe.data.len()/CLUSTER_RATIO).src(&recipe.data),
+                  &mut recipe.data.clone(),
+                  &recipe.commands)
+}
 
 #[quickcheck]
 fn insert_remove_scattered_empty(recipe: InsertRemoveScatteredEmpty<i32>) -> bool {
@@ -239,3 +258,775 @@ fn push(data: Vec<i32>) -> bool {
 
     table_slice_equal(&table, &expected)
 }
+
+#[test]
+fn extend_then_edit() {
+    let mut table = PieceTable::new().history();
+
+    // Extending with nothing records no revision.
+    table.extend(vec![]);
+    assert!(!table.undo());
+
+    table.insert(0, 1);
+    table.extend(vec![2, 3]);
+    table.extend(vec![]);
+    table.insert(1, 4);
+    table.remove(3);
+    table.remove(2);
+
+    assert_eq!(vec![&1, &4],
+               table.iter().collect::<Vec<&i32>>());
+}
+
+#[quickcheck]
+fn undo_redo(recipe: InsertRemoveClusteredGiven<i32>) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data).history();
+    let mut expected = recipe.data.clone();
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+    let edited = table_slice_equal(&table, &expected);
+
+    while table.undo() {}
+    let undone = table_slice_equal(&table, &recipe.data);
+
+    while table.redo() {}
+    edited && undone && table_slice_equal(&table, &expected)
+}
+
+#[quickcheck]
+fn transaction_undo(recipe: InsertRemoveClusteredGiven<i32>) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data).history();
+    let mut expected = recipe.data.clone();
+
+    table.transact(|t| run_commands(t, &mut expected, &recipe.commands));
+    let edited = table_slice_equal(&table, &expected);
+
+    table.undo();
+    edited && table_slice_equal(&table, &recipe.data) && !table.undo()
+}
+
+#[test]
+fn transaction_rollback_on_panic() {
+    use std::panic;
+
+    let src = vec![1, 2, 3];
+    let mut table = PieceTable::new().src(&src);
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        table.transact(|t| {
+            t.remove(0);
+            t.push(4);
+            panic!("abandon edit");
+        })
+    }));
+
+    assert!(result.is_err());
+    assert_eq!(vec![&1, &2, &3],
+               table.iter().collect::<Vec<&i32>>());
+}
+
+#[test]
+fn undo_tree_branches() {
+    let mut table = PieceTable::new().history();
+
+    table.push(1);
+    table.push(2);
+    table.undo();
+    table.push(3);
+
+    assert_eq!(3, table.revision());
+    assert_eq!(&[2, 3], table.revision_children(1));
+    assert_eq!(Some(1), table.revision_parent(3));
+
+    assert!(table.earlier());
+    assert_eq!(vec![&1, &2], table.iter().collect::<Vec<&i32>>());
+
+    assert!(table.goto_revision(0));
+    assert!(table.is_empty());
+
+    assert!(table.later());
+    assert!(table.redo());
+    assert_eq!(vec![&1, &2], table.iter().collect::<Vec<&i32>>());
+    assert!(!table.goto_revision(4));
+}
+
+#[quickcheck]
+fn insert_slice(recipe: InsertRemoveScatteredGiven<i32>, slices: Vec<Vec<i32>>) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data);
+    let mut expected = recipe.data.clone();
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    for slice in slices {
+        let idx = slice.len() % (expected.len() + 1);
+        table.insert_slice(idx, &slice);
+
+        for (i, &x) in slice.iter().enumerate() {
+            expected.insert(idx+i, x);
+        }
+    }
+
+    table_slice_equal(&table, &expected)
+}
+
+#[quickcheck]
+fn insert_iter(recipe: InsertRemoveClusteredGiven<i32>, slices: Vec<Vec<i32>>) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data);
+    let mut expected = recipe.data.clone();
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    for slice in slices {
+        let idx = expected.len() - slice.len() % (expected.len() + 1);
+
+        for (i, &x) in slice.iter().enumerate() {
+            expected.insert(idx+i, x);
+        }
+
+        table.insert_iter(idx, slice);
+    }
+
+    table_slice_equal(&table, &expected)
+}
+
+#[quickcheck]
+fn drain_ranges(recipe: Ranges<i32>) -> bool {
+    let mut table = PieceTable::with_capacity(recipe.elements, recipe.elements);
+    let mut expected = Vec::with_capacity(recipe.elements);
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    recipe.ranges.into_iter().all(|range| {
+        let mut table = table.clone();
+        let mut expected = expected.clone();
+
+        let drained = table.drain(range).map(|&x| x).collect::<Vec<i32>>();
+        let removed = expected.drain(range).collect::<Vec<i32>>();
+
+        drained == removed && table_slice_equal(&table, &expected)
+    })
+}
+
+#[quickcheck]
+fn splice_ranges(recipe: Ranges<i32>, replacement: Vec<i32>) -> bool {
+    let mut table = PieceTable::with_capacity(recipe.elements, recipe.elements).history();
+    let mut expected = Vec::with_capacity(recipe.elements);
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    recipe.ranges.into_iter().all(|range| {
+        let mut table = table.clone();
+        let mut spliced = expected.clone();
+        let revision = table.revision();
+
+        let removed = table.splice(range, replacement.iter().map(|&x| x))
+            .map(|&x| x).collect::<Vec<i32>>();
+        let expected_removed = spliced.splice(range, replacement.iter().map(|&x| x))
+            .collect::<Vec<i32>>();
+
+        let equal = removed == expected_removed && table_slice_equal(&table, &spliced);
+        table.goto_revision(revision);
+
+        equal && table_slice_equal(&table, &expected)
+    })
+}
+
+#[quickcheck]
+fn cursor_edits(recipe: InsertRemoveScatteredGiven<i32>, moves: Vec<(u8, i32)>) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data);
+    let mut expected = recipe.data.clone();
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    let mut pos = expected.len() / 2;
+    let moved = {
+        let mut cursor = table.cursor_mut(pos);
+
+        moves.into_iter().all(|(op, value)| {
+            match op % 5 {
+                0 => if cursor.move_left() { pos -= 1 },
+                1 => if cursor.move_right() { pos += 1 },
+                2 => {
+                    cursor.insert(value);
+                    expected.insert(pos, value);
+                    pos += 1;
+                },
+                3 => if cursor.delete_forward() { expected.remove(pos); },
+                _ => if cursor.delete_backward() {
+                    pos -= 1;
+                    expected.remove(pos);
+                },
+            }
+
+            cursor.position() == pos && cursor.get() == expected.get(pos)
+        })
+    };
+
+    moved && table_slice_equal(&table, &expected)
+}
+
+#[test]
+fn owned_source() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let src: Arc<[i32]> = vec![1, 2, 3].into();
+    let mut table = PieceTable::new().source(src.clone()).history();
+
+    table.insert(1, 4);
+    table.remove(3);
+
+    let edited = thread::spawn(move || {
+        table.undo();
+        table.iter().map(|&x| x).collect::<Vec<i32>>()
+    });
+
+    assert_eq!(vec![1, 4, 2, 3], edited.join().unwrap());
+    assert_eq!(&[1, 2, 3], &*src);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_source() {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join(format!("piecetable_mmap_source_{}", std::process::id()));
+    File::create(&path).unwrap().write_all(b"hello world").unwrap();
+
+    let mut table = PieceTable::open(&path).unwrap();
+    table.insert(5, b',');
+    assert_eq!(b"hello, world".to_vec(), table.iter().map(|&x| x).collect::<Vec<u8>>());
+
+    // The table reads a copy, so truncating the file does not affect it.
+    File::create(&path).unwrap();
+    assert_eq!(b"hello, world".to_vec(), table.iter().map(|&x| x).collect::<Vec<u8>>());
+
+    fs::remove_file(&path).unwrap();
+}
+
+/// Accepts at most `limit` bytes per call, to exercise partial writes.
+struct ShortWriter {
+    written: Vec<u8>,
+    limit: usize,
+}
+
+impl std::io::Write for ShortWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = std::cmp::min(buf.len(), self.limit);
+        self.written.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice]) -> std::io::Result<usize> {
+        let mut n = 0;
+        for buf in bufs {
+            n += self.write(&buf[..std::cmp::min(buf.len(), self.limit - n)])?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[quickcheck]
+fn write_to(recipe: InsertRemoveScatteredGiven<u8>, limit: usize) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data);
+    let mut expected = recipe.data.clone();
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    let mut writer = ShortWriter { written: Vec::new(), limit: limit % 100 + 1 };
+    table.write_to(&mut writer).unwrap();
+
+    writer.written == expected
+}
+
+#[quickcheck]
+fn write_to_utf8(recipe: InsertRemoveScatteredGiven<char>) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data);
+    let mut expected = recipe.data.clone();
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    let mut written = Vec::new();
+    table.write_to(&mut written).unwrap();
+
+    written == expected.into_iter().collect::<String>().into_bytes()
+}
+
+#[quickcheck]
+fn chunks_ranges(recipe: Ranges<i32>) -> bool {
+    let mut table = PieceTable::with_capacity(recipe.elements, recipe.elements);
+    let mut expected = Vec::with_capacity(recipe.elements);
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    recipe.ranges.into_iter().all(|range| {
+        let chunks = table.chunks_in(range).collect::<Vec<&[i32]>>();
+
+        chunks.iter().all(|chunk| !chunk.is_empty()) && chunks.concat() == &expected[range]
+    })
+}
+
+#[quickcheck]
+fn double_ended_ranges(recipe: Ranges<i32>, steps: Vec<(u8, usize)>) -> bool {
+    let mut table = PieceTable::with_capacity(recipe.elements, recipe.elements);
+    let mut expected = Vec::with_capacity(recipe.elements);
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    recipe.ranges.into_iter().all(|(from, to)| {
+        let mut range = table.range(from, to);
+        let mut expected_range = expected[(from, to)].iter();
+
+        steps.iter().all(|&(op, n)| {
+            let n = n % 8;
+            let equal = match op % 4 {
+                0 => range.next() == expected_range.next(),
+                1 => range.next_back() == expected_range.next_back(),
+                2 => range.nth(n) == expected_range.nth(n),
+                _ => range.nth_back(n) == expected_range.nth_back(n),
+            };
+
+            equal && range.len() == expected_range.len()
+        })
+    })
+}
+
+#[quickcheck]
+fn line_index(recipe: InsertRemoveScatteredGiven<u8>) -> bool {
+    let recipe = with_line_breaks(recipe);
+    let mut table = PieceTable::new().src(&recipe.data).line_index();
+    let mut expected = recipe.data.clone();
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+
+    let starts = Some(0).into_iter()
+        .chain(expected.iter().enumerate().filter(|&(_, &x)| x == b'\n').map(|(i, _)| i + 1))
+        .collect::<Vec<usize>>();
+
+    let lines = table.lines().map(|line| line.map(|&x| x).collect::<Vec<u8>>()).collect::<Vec<Vec<u8>>>();
+    let expected_lines = expected.split(|&x| x == b'\n').map(|line| line.to_vec()).collect::<Vec<Vec<u8>>>();
+
+    table.line_count() == starts.len()
+        && lines == expected_lines
+        && starts.iter().enumerate().all(|(line, &start)| table.line_to_offset(line) == start)
+        && (0 .. expected.len() + 1).all(|idx| {
+            let line = starts.iter().rposition(|&start| start <= idx).unwrap();
+            table.offset_to_line_col(idx) == (line, idx - starts[line])
+        })
+}
+
+#[quickcheck]
+fn line_index_history(recipe: InsertRemoveScatteredGiven<u8>) -> bool {
+    let recipe = with_line_breaks(recipe);
+    let line_count = |elements: &[u8]| elements.iter().filter(|&&x| x == b'\n').count() + 1;
+
+    let mut table = PieceTable::new().src(&recipe.data).history();
+    let mut expected = recipe.data.clone();
+    let mut revisions = vec![expected.clone()];
+
+    for &cmd in &recipe.commands {
+        run_commands(&mut table, &mut expected, &[cmd]);
+        revisions.push(expected.clone());
+    }
+
+    // Enable the index in the middle of a transaction, after the revisions were recorded without it.
+    table.begin_transaction();
+    table.push(b'\n');
+    let mut table = table.line_index();
+    let rolled_back = table.rollback() && table_slice_equal(&table, &expected) && table.line_count() == line_count(&expected);
+
+    rolled_back && revisions.iter().rev().all(|expected| {
+        let equal = table_slice_equal(&table, expected) && table.line_count() == line_count(expected);
+        table.undo();
+        equal
+    })
+}
+
+#[quickcheck]
+fn text_table(src: String, edits: Vec<(usize, usize, String)>) -> bool {
+    // Move an arbitrary index to the closest preceding character boundary.
+    let boundary = |string: &String, idx: usize| {
+        let mut idx = idx % (string.len() + 1);
+        while !string.is_char_boundary(idx) {
+            idx -= 1;
+        }
+        idx
+    };
+
+    let mut table = TextTable::new().src(&src);
+    let mut expected = src.clone();
+
+    for (a, b, string) in edits {
+        let (a, b) = (boundary(&expected, a), boundary(&expected, b));
+        let (from, to) = (a.min(b), a.max(b));
+
+        if from == to && from < expected.len() && string.is_empty() {
+            if table.remove(from) != expected.remove(from) {
+                return false;
+            }
+        } else {
+            table.replace_range(from .. to, &string);
+            expected.replace_range(from .. to, &string);
+        }
+    }
+
+    table.to_string() == expected
+        && table.chars().rev().eq(expected.chars().rev())
+        && table.char_indices().eq(expected.char_indices())
+        && (0 .. expected.len() + 2).all(|idx| table.is_char_boundary(idx) == expected.is_char_boundary(idx))
+        && expected.char_indices().map(|(idx, _)| idx).chain(Some(expected.len())).enumerate()
+            .all(|(n, idx)| table.byte_to_char(idx) == n && table.char_to_byte(n) == idx)
+}
+
+#[quickcheck]
+fn lsp_positions(src: String, inserts: Vec<(usize, String)>) -> bool {
+    let src = src.chars().collect::<Vec<char>>();
+    // The offset between the halves of a `"\r\n"` line break is past the end of its line, which ends before them.
+    let clamp = |idx: usize| if idx > 0 && src[idx - 1] == '\r' && src.get(idx) == Some(&'\n') { idx - 1 } else { idx };
+    let inserts = inserts.into_iter().map(|(idx, text)| (clamp(idx % (src.len() + 1)), text)).collect::<Vec<(usize, String)>>();
+    let mut table = PieceTable::new().src(&src).line_index();
+
+    let encodings = [PositionEncoding::Utf8, PositionEncoding::Utf16];
+    let round_trips = encodings.iter().all(|&encoding| (0 .. src.len() + 1).all(|idx| {
+        let position = table.offset_to_position(idx, encoding);
+        let start = src[..idx].iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1);
+
+        position.line == src[..idx].iter().filter(|&&c| c == '\n').count()
+            && position.character == src[start..idx].iter().map(|&c| encoding.units(c)).sum()
+            && table.position_to_offset(position, encoding) == clamp(idx)
+    }));
+
+    let edits = inserts.iter().map(|&(idx, ref text)| {
+        let position = table.offset_to_position(idx, PositionEncoding::Utf16);
+        TextEdit { range: position .. position, new_text: text.clone() }
+    }).collect::<Vec<TextEdit>>();
+
+    // Positions refer to the text before any of the edits, and inserts at the same position keep their order.
+    let mut order = (0 .. inserts.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&i| (inserts[i].0, i));
+    let mut expected = src.clone();
+    for &i in order.iter().rev() {
+        let (idx, ref text) = inserts[i];
+        expected.splice(idx .. idx, text.chars());
+    }
+
+    round_trips
+        && table.apply_edits(&edits, PositionEncoding::Utf16)
+        && table.iter().cloned().collect::<Vec<char>>() == expected
+}
+
+#[test]
+fn lsp_crlf() {
+    let src: Vec<char> = "ab\r\ncd\r\n".chars().collect();
+    let mut table = PieceTable::new().src(&src).line_index();
+
+    // A column past the end of a line ends up before its `"\r\n"`, never between the halves.
+    assert_eq!(2, table.position_to_offset(Position::new(0, 2), PositionEncoding::Utf16));
+    assert_eq!(2, table.position_to_offset(Position::new(0, 3), PositionEncoding::Utf16));
+    assert_eq!(6, table.position_to_offset(Position::new(1, 80), PositionEncoding::Utf16));
+    assert_eq!(8, table.position_to_offset(Position::new(2, 0), PositionEncoding::Utf16));
+
+    let edits = vec![TextEdit { range: Position::new(0, 80) .. Position::new(0, 80), new_text: "!".to_string() }];
+    assert!(table.apply_edits(&edits, PositionEncoding::Utf16));
+    assert_eq!("ab!\r\ncd\r\n", table.iter().collect::<String>());
+}
+
+#[quickcheck]
+fn anchors(len: usize, anchors: Vec<(usize, bool)>, edits: Vec<(usize, usize, usize)>, undos: usize) -> bool {
+    // Every element is distinct, so the element an anchor follows can be looked up by value.
+    let src = (0 .. len % 100).collect::<Vec<usize>>();
+    let mut table = PieceTable::new().src(&src).history();
+    let mut next = src.len();
+
+    let anchors = anchors.into_iter().map(|(idx, left)| {
+        let idx = idx % (src.len() + 1);
+        let (gravity, element) = if left {
+            (Gravity::Left, if idx > 0 { Some(idx - 1) } else { None })
+        } else {
+            (Gravity::Right, if idx < src.len() { Some(idx) } else { None })
+        };
+        (table.create_anchor(idx, gravity), gravity, element)
+    }).collect::<Vec<(Anchor, Gravity, Option<usize>)>>();
+
+    let all_attached = |table: &PieceTable<usize>| {
+        let expected = table.iter().cloned().collect::<Vec<usize>>();
+
+        anchors.iter().all(|&(anchor, gravity, element)| {
+            let offset = table.anchor_offset(anchor);
+            match (element.and_then(|element| expected.iter().position(|&x| x == element)), gravity) {
+                (Some(idx), Gravity::Left) => !table.anchor_deleted(anchor) && offset == idx + 1,
+                (Some(idx), Gravity::Right) => !table.anchor_deleted(anchor) && offset == idx,
+                (None, _) if element.is_some() => table.anchor_deleted(anchor) && offset <= expected.len(),
+                (None, Gravity::Left) => !table.anchor_deleted(anchor) && offset == 0,
+                (None, Gravity::Right) => !table.anchor_deleted(anchor) && offset == expected.len(),
+            }
+        })
+    };
+
+    let edited = edits.into_iter().all(|(a, b, count)| {
+        let (a, b) = (a % (table.len() + 1), b % (table.len() + 1));
+        let new = (next .. next + count % 4).collect::<Vec<usize>>();
+        next += count % 4;
+
+        table.splice(a.min(b) .. a.max(b), new);
+        all_attached(&table)
+    });
+
+    edited && (0 .. undos % 8).all(|_| {
+        table.undo();
+        all_attached(&table)
+    })
+}
+
+#[test]
+fn source_detaches_anchors() {
+    let mut table = PieceTable::new();
+    table.extend(vec![1, 2, 3]);
+    let start = table.create_anchor(0, Gravity::Left);
+    let middle = table.create_anchor(2, Gravity::Right);
+    let end = table.create_anchor(3, Gravity::Right);
+
+    let src = vec![4, 5];
+    let mut table = table.src(&src);
+    table.insert(0, 6);
+
+    assert_eq!(0, table.anchor_offset(start));
+    assert!(!table.anchor_deleted(start));
+    assert_eq!(1, table.anchor_offset(middle));
+    assert!(table.anchor_deleted(middle));
+    assert_eq!(3, table.anchor_offset(end));
+    assert!(!table.anchor_deleted(end));
+}
+
+#[quickcheck]
+fn decorations(len: usize, ranges: Vec<(usize, usize, bool, bool)>, edits: Vec<(usize, usize, usize)>, query: (usize, usize)) -> bool {
+    let src = vec![0; len % 100];
+    let mut table = PieceTable::new().src(&src);
+    let mut decorations = Decorations::new();
+
+    let gravity = |left| if left { Gravity::Left } else { Gravity::Right };
+    let handles = ranges.into_iter().enumerate().map(|(i, (a, b, start, end))| {
+        let (a, b) = (a % (src.len() + 1), b % (src.len() + 1));
+        (decorations.insert(&mut table, a.min(b) .. a.max(b), gravity(start), gravity(end), i), i)
+    }).collect::<Vec<(Decoration, usize)>>();
+
+    for (a, b, count) in edits {
+        let (a, b) = (a % (table.len() + 1), b % (table.len() + 1));
+        table.splice(a.min(b) .. a.max(b), vec![1; count % 4]);
+    }
+
+    let (a, b) = (query.0 % (table.len() + 1), query.1 % (table.len() + 1));
+    let (from, to) = (a.min(b), a.max(b));
+
+    let mut expected = handles.iter()
+        .map(|&(handle, i)| (decorations.range(&table, handle).unwrap(), i))
+        .filter(|&(ref range, _)| range.start < range.end && range.start < to && from < range.end)
+        .collect::<Vec<(std::ops::Range<usize>, usize)>>();
+    let mut found = decorations.intersecting(&table, from .. to)
+        .map(|(range, &i)| (range, i))
+        .collect::<Vec<(std::ops::Range<usize>, usize)>>();
+
+    let ordered = found.windows(2).all(|pair| pair[0].0.start <= pair[1].0.start);
+    expected.sort_by_key(|&(_, i)| i);
+    found.sort_by_key(|&(_, i)| i);
+
+    ordered && found == expected
+}
+
+#[quickcheck]
+fn edit_events(recipe: InsertRemoveScatteredGiven<usize>, undos: usize) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data).history();
+    let subscription = table.subscribe();
+    let mut expected = recipe.data.clone();
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+    for _ in 0 .. undos % 8 {
+        table.undo();
+    }
+
+    // Replaying the edits with placeholders for the inserted elements must keep every other element.
+    let mut replayed = recipe.data.iter().map(|&x| Some(x)).collect::<Vec<Option<usize>>>();
+    for edit in table.poll_edits(subscription).to_vec() {
+        replayed.splice(edit.start .. edit.start + edit.removed_len, vec![None; edit.inserted_len]);
+    }
+
+    replayed.len() == table.len()
+        && replayed.iter().zip(table.iter()).all(|(replayed, &x)| replayed.map_or(true, |y| y == x))
+        && table.poll_edits(subscription).is_empty()
+}
+
+#[quickcheck]
+fn input_edits(recipe: InsertRemoveScatteredGiven<u8>) -> bool {
+    let recipe = with_line_breaks(recipe);
+    let point = |elements: &[u8], idx: usize| Point {
+        row: elements[..idx].iter().filter(|&&x| x == b'\n').count(),
+        column: idx - elements[..idx].iter().rposition(|&x| x == b'\n').map_or(0, |i| i + 1),
+    };
+
+    let mut table = PieceTable::new().src(&recipe.data).line_index();
+    let subscription = table.subscribe();
+    let mut expected = recipe.data.clone();
+
+    recipe.commands.iter().all(|&cmd| {
+        let before = expected.clone();
+        run_commands(&mut table, &mut expected, &[cmd]);
+
+        let edits = table.poll_input_edits(subscription);
+        edits.len() == 1 && edits.iter().all(|edit| {
+            edit.start_position == point(&before, edit.start_byte)
+                && edit.old_end_position == point(&before, edit.old_end_byte)
+                && edit.new_end_position == point(&expected, edit.new_end_byte)
+        })
+    }) && (0 .. expected.len()).all(|idx| {
+        let chunk = table.chunk_at(idx);
+        !chunk.is_empty() && chunk == &expected[idx .. idx + chunk.len()]
+    })
+}
+
+#[quickcheck]
+fn snapshots(recipe: InsertRemoveScatteredGiven<usize>, interval: usize) -> bool {
+    let interval = interval % 4 + 1;
+    let mut table = PieceTable::new().src(&recipe.data);
+    let mut expected = recipe.data.clone();
+    let mut snapshots = Vec::new();
+
+    for (i, cmd) in recipe.commands.chunks(interval).enumerate() {
+        run_commands(&mut table, &mut expected, cmd);
+        if i % 2 == 0 {
+            table.extend(cmd.iter().filter_map(|&cmd| match cmd { Insert(_, value) => Some(value), Remove(_) => None }));
+            expected.extend(cmd.iter().filter_map(|&cmd| match cmd { Insert(_, value) => Some(value), Remove(_) => None }));
+        }
+        snapshots.push((table.snapshot(), expected.clone()));
+    }
+
+    table_slice_equal(&table, &expected) && snapshots.iter().all(|&(ref snapshot, ref expected)| {
+        snapshot.len() == expected.len()
+            && snapshot.iter().collect::<Vec<&usize>>() == expected.iter().collect::<Vec<&usize>>()
+            && snapshot.iter().rev().collect::<Vec<&usize>>() == expected.iter().rev().collect::<Vec<&usize>>()
+            && snapshot.chunks().flat_map(|chunk| chunk.iter()).eq(expected.iter())
+            && (0 .. expected.len()).all(|idx| snapshot[idx] == expected[idx])
+    })
+}
+
+#[test]
+fn snapshots_across_edits() {
+    let src: Vec<usize> = Vec::new();
+    let mut table = PieceTable::new().src(&src);
+
+    for i in 0..20000 {
+        let snapshot = table.snapshot();
+        table.insert(i, i);
+        assert_eq!(i, snapshot.len());
+    }
+
+    // The table keeps appending to the chunks the snapshots share, so the elements of the piece stay in few chunks.
+    assert_eq!(1, table.piece_count());
+    assert!(table.chunks().count() <= 16);
+    assert!(table.chunks().flat_map(|chunk| chunk.iter()).cloned().eq(0..20000));
+}
+
+#[quickcheck]
+fn clones(recipe: InsertRemoveScatteredGiven<u8>, interval: usize) -> bool {
+    let recipe = with_line_breaks(recipe);
+    let line_count = |elements: &[u8]| elements.iter().filter(|&&x| x == b'\n').count() + 1;
+
+    let interval = interval % 4 + 1;
+    let mut table = PieceTable::new().src(&recipe.data).line_index();
+    let mut expected = recipe.data.clone();
+    let mut clones = Vec::new();
+
+    for cmd in recipe.commands.chunks(interval) {
+        run_commands(&mut table, &mut expected, cmd);
+
+        // Edit the clone at its end, where every command is valid.
+        let mut clone = table.clone();
+        let mut clone_expected = expected.clone();
+        for &cmd in cmd {
+            match cmd {
+                Insert(_, value) => {
+                    clone.push(value);
+                    clone_expected.push(value);
+                },
+                Remove(_) => if !clone_expected.is_empty() {
+                    clone.remove(clone_expected.len() - 1);
+                    clone_expected.pop();
+                },
+            }
+        }
+        clones.push((clone, clone_expected));
+    }
+
+    table_slice_equal(&table, &expected) && table.line_count() == line_count(&expected)
+        && clones.iter().all(|&(ref clone, ref expected)| {
+            table_slice_equal(clone, expected) && clone.line_count() == line_count(expected)
+        })
+}
+
+#[test]
+fn clones_across_edits() {
+    let src = b"".to_vec();
+    let mut table = PieceTable::new().src(&src);
+    let mut clones = Vec::new();
+
+    for i in 0..20000 {
+        clones.push(table.clone());
+        table.insert(i, b'a' + (i % 26) as u8);
+    }
+
+    // Neither the table nor its clones split the piece they share into a slice per edit.
+    let expected = (0..20000).map(|i| b'a' + (i % 26) as u8).collect::<Vec<u8>>();
+    let mut saved = Vec::new();
+    table.write_to(&mut saved).unwrap();
+    assert_eq!(expected, saved);
+    assert!(table.chunks().count() <= 16);
+    assert!(clones.iter().all(|clone| clone.chunks().count() <= 16));
+}
+
+#[quickcheck]
+fn compaction(recipe: InsertRemoveScatteredGiven<usize>, interval: usize) -> bool {
+    let interval = interval % 4 + 1;
+    let mut table = PieceTable::new().src(&recipe.data).history();
+    let mut expected = recipe.data.clone();
+    let mut revisions = vec![expected.clone()];
+
+    for cmd in recipe.commands.chunks(interval) {
+        for &cmd in cmd {
+            run_commands(&mut table, &mut expected, &[cmd]);
+            revisions.push(expected.clone());
+        }
+
+        let reclaimable = table.reclaimable();
+        if table.compact() != reclaimable || table.reclaimable() != 0 || !table_slice_equal(&table, &expected) {
+            return false;
+        }
+    }
+
+    revisions.iter().rev().skip(1).all(|revision| table.undo() && table_slice_equal(&table, revision))
+        && !table.undo()
+}
+
+#[quickcheck]
+fn coalescing(recipe: InsertRemoveScatteredGiven<usize>) -> bool {
+    let mut table = PieceTable::new().src(&recipe.data);
+    let mut expected = recipe.data.clone();
+    let pieces = if recipe.data.is_empty() { 0 } else { 1 };
+
+    // Removing an element right after inserting it leaves the source in one piece.
+    let restored = recipe.commands.iter().all(|&cmd| match cmd {
+        Insert(idx, value) => {
+            let idx = idx.min(table.len());
+            table.insert(idx, value);
+            table.remove(idx);
+            table.piece_count() == pieces
+        },
+        Remove(_) => true,
+    });
+
+    run_commands(&mut table, &mut expected, &recipe.commands);
+    restored && table.defragment() == 0 && table_slice_equal(&table, &expected)
+}